@@ -0,0 +1,199 @@
+//! ALSA sequencer backend for the MIDI clock (Linux, `alsa-seq` feature).
+//!
+//! Instead of writing each message when it is due, clock messages are put on
+//! an ALSA sequencer queue up to [`LOOKAHEAD`] ahead with real-time
+//! timestamps, so the kernel sends them on time regardless of how promptly
+//! the clock thread wakes up. When the plan changes (tempo, transport) the
+//! queued messages are removed and scheduled again.
+
+use crate::midi_clock::{ClockSink, Message, MidiClockError, PortSelector};
+use alsa::seq::{
+    Addr, ClientIter, EvCtrl, EvQueueControl, Event, EventType, PortCap, PortIter, PortSubscribe,
+    PortType, Remove, RemoveEvents, Seq,
+};
+use alsa::Direction;
+use regex::Regex;
+use std::{
+    error::Error,
+    ffi::CString,
+    time::{Duration, Instant},
+};
+
+/// How far ahead clock messages are queued.
+const LOOKAHEAD: Duration = Duration::from_millis(100);
+const CLIENT_NAME: &str = "x1-tap-clock";
+const DEFAULT_PORT_NAME: &str = "clock";
+
+/// A sequencer client with one output port and a running queue.
+pub(crate) struct AlsaSeqSink {
+    seq: Seq,
+    port: i32,
+    queue: i32,
+    /// The instant at which the queue's real time was zero.
+    queue_start: Instant,
+    port_name: String,
+}
+
+impl AlsaSeqSink {
+    /// Create the client, its port and queue. A [`PortSelector::Virtual`]
+    /// port is left for other applications to subscribe to; any other
+    /// selector picks a writable sequencer port to connect to.
+    pub(crate) fn open(selector: &PortSelector) -> Result<Self, MidiClockError> {
+        let init = |err: alsa::Error| MidiClockError::MidiInit(err.to_string());
+
+        let seq = Seq::open(None, Some(Direction::Playback), false).map_err(init)?;
+        seq.set_client_name(&c_string(CLIENT_NAME)).map_err(init)?;
+
+        let own_name = match selector {
+            PortSelector::Virtual(name) => name.as_str(),
+            _ => DEFAULT_PORT_NAME,
+        };
+        let port = seq
+            .create_simple_port(
+                &c_string(own_name),
+                PortCap::READ | PortCap::SUBS_READ,
+                PortType::MIDI_GENERIC | PortType::APPLICATION,
+            )
+            .map_err(init)?;
+
+        let port_name = match selector {
+            PortSelector::Virtual(name) => format!("{CLIENT_NAME}:{name} (virtual)"),
+            _ => {
+                let (dest, name) = find_destination(&seq, selector)
+                    .ok_or_else(|| MidiClockError::PortNotFound(selector.to_string()))?;
+                connect(&seq, port, dest)
+                    .map_err(|err| MidiClockError::Connection(err.to_string()))?;
+                name
+            }
+        };
+
+        let queue = seq
+            .alloc_named_queue(&c_string(CLIENT_NAME))
+            .map_err(init)?;
+        seq.control_queue(queue, EventType::Start, 0, None)
+            .map_err(init)?;
+        seq.drain_output().map_err(init)?;
+        let elapsed = seq
+            .get_queue_status(queue)
+            .map(|status| status.get_real_time())
+            .map_err(init)?;
+        let now = Instant::now();
+        let queue_start = now.checked_sub(elapsed).unwrap_or(now);
+
+        Ok(Self {
+            seq,
+            port,
+            queue,
+            queue_start,
+            port_name,
+        })
+    }
+
+    pub(crate) fn port_name(&self) -> &str {
+        &self.port_name
+    }
+}
+
+impl ClockSink for AlsaSeqSink {
+    fn lookahead(&self) -> Duration {
+        LOOKAHEAD
+    }
+
+    fn send(&mut self, message: Message, at: Instant) -> Result<(), Box<dyn Error>> {
+        let control = EvQueueControl {
+            queue: self.queue,
+            value: (),
+        };
+        let mut event = match message {
+            Message::Clock => Event::new(EventType::Clock, &control),
+            Message::Start => Event::new(EventType::Start, &control),
+            Message::Continue => Event::new(EventType::Continue, &control),
+            Message::Stop => Event::new(EventType::Stop, &control),
+            Message::SongPosition(position) => Event::new(
+                EventType::Songpos,
+                &EvCtrl {
+                    channel: 0,
+                    param: 0,
+                    value: i32::from(position),
+                },
+            ),
+        };
+        event.set_source(self.port);
+        event.set_subs();
+        if at > Instant::now() {
+            event.schedule_real(self.queue, false, at.duration_since(self.queue_start));
+        } else {
+            event.set_direct();
+        }
+        self.seq.event_output(&mut event)?;
+        self.seq.drain_output()?;
+        Ok(())
+    }
+
+    fn revoke(&mut self, after: Instant) -> Result<(), Box<dyn Error>> {
+        let remove = RemoveEvents::new()?;
+        remove.set_condition(Remove::OUTPUT | Remove::TIME_AFTER);
+        remove.set_queue(self.queue);
+        remove.set_time(after.saturating_duration_since(self.queue_start));
+        self.seq.remove_events(remove)?;
+        Ok(())
+    }
+}
+
+impl Drop for AlsaSeqSink {
+    fn drop(&mut self) {
+        let _ = self.seq.control_queue(self.queue, EventType::Stop, 0, None);
+        let _ = self.seq.drain_output();
+        let _ = self.seq.free_queue(self.queue);
+    }
+}
+
+/// Writable sequencer port picked by `selector`, with its
+/// `client:port` name.
+fn find_destination(seq: &Seq, selector: &PortSelector) -> Option<(Addr, String)> {
+    let own_client = seq.client_id().ok()?;
+    let mut ports = ClientIter::new(seq)
+        .filter(|client| client.get_client() != own_client)
+        .flat_map(|client| {
+            let client_name = client.get_name().unwrap_or_default().to_string();
+            PortIter::new(seq, client.get_client())
+                .filter(|port| {
+                    port.get_capability()
+                        .contains(PortCap::WRITE | PortCap::SUBS_WRITE)
+                })
+                .map(|port| {
+                    let name = format!("{}:{}", client_name, port.get_name().unwrap_or_default());
+                    (port.addr(), name)
+                })
+                .collect::<Vec<_>>()
+        });
+
+    match selector {
+        PortSelector::Name(hint) if hint.trim().is_empty() => ports.next(),
+        PortSelector::Name(hint) => {
+            let hint = hint.to_lowercase();
+            ports.find(|(_, name)| name.to_lowercase().contains(&hint))
+        }
+        PortSelector::Regex(pattern) => {
+            let regex = Regex::new(pattern).ok()?;
+            ports.find(|(_, name)| regex.is_match(name))
+        }
+        PortSelector::Index(index) => ports.nth(*index),
+        PortSelector::Virtual(_) => None,
+    }
+}
+
+fn connect(seq: &Seq, port: i32, dest: Addr) -> alsa::Result<()> {
+    let subscription = PortSubscribe::empty()?;
+    subscription.set_sender(Addr {
+        client: seq.client_id()?,
+        port,
+    });
+    subscription.set_dest(dest);
+    seq.subscribe_port(&subscription)
+}
+
+/// Port and client names come from our own config; interior NULs are dropped.
+fn c_string(text: &str) -> CString {
+    CString::new(text.replace('\0', "")).unwrap_or_default()
+}
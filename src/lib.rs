@@ -0,0 +1,30 @@
+//! Building blocks of `x1-tap-clock`: a Kontrol X1 Mk1 driver, tap tempo
+//! detection, a MIDI clock generator and an Ableton Link wrapper.
+//!
+//! The `x1-tap-clock` binary is a thin layer on top of this crate; other tools
+//! can use the same pieces directly:
+//!
+//! ```no_run
+//! use x1_tap_clock::x1_controller::{ButtonEventKind, X1Controller};
+//!
+//! let mut controller = X1Controller::connect_supervised()?;
+//! controller.set_button_callback(|_, event, _, _| {
+//!     if event.kind == ButtonEventKind::Pressed {
+//!         println!("{:?}", event.id);
+//!     }
+//! });
+//! controller.run()?;
+//! # Ok::<(), rusb::Error>(())
+//! ```
+
+#[cfg(all(target_os = "linux", feature = "alsa-seq"))]
+mod alsa_seq;
+pub mod link_controller;
+pub mod mapping;
+pub mod midi_clock;
+pub mod midi_clock_input;
+pub mod midi_surface;
+pub mod tap_tempo;
+pub mod tempo;
+pub mod timeline;
+pub mod x1_controller;
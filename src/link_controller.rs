@@ -1,4 +1,63 @@
+//! Ableton Link session wrapper.
+
+use crate::tempo::clamp_bpm;
+use crate::timeline::Timeline;
 use ableton_link::{Clock, Link, SessionState};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Tempo differences below this are treated as rounding, not as a change.
+const TEMPO_EPSILON: f64 = 1e-3;
+
+/// Bumped from the Link callbacks. They carry no user data, so the
+/// generation is process-wide and each controller remembers what it has seen.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn on_num_peers(_peers: usize) {
+    GENERATION.fetch_add(1, Ordering::Release);
+}
+
+extern "C" fn on_tempo(_bpm: f64) {
+    GENERATION.fetch_add(1, Ordering::Release);
+}
+
+extern "C" fn on_start_stop(_playing: bool) {
+    GENERATION.fetch_add(1, Ordering::Release);
+}
+
+/// A change to the Link session, as seen by [`LinkController::poll_events`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkEvent {
+    Tempo(f64),
+    Playing(bool),
+    Peers(usize),
+}
+
+/// The parts of the session that [`LinkEvent`]s report on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Snapshot {
+    tempo: f64,
+    playing: bool,
+    peers: usize,
+}
+
+impl Snapshot {
+    fn changes(&self, next: &Snapshot) -> Vec<LinkEvent> {
+        let mut events = Vec::new();
+        if (next.tempo - self.tempo).abs() > TEMPO_EPSILON {
+            events.push(LinkEvent::Tempo(next.tempo));
+        }
+        if next.playing != self.playing {
+            events.push(LinkEvent::Playing(next.playing));
+        }
+        if next.peers != self.peers {
+            events.push(LinkEvent::Peers(next.peers));
+        }
+        events
+    }
+}
 
 /// Simple wrapper around the `ableton_link` crate that provides a convenient,
 /// ergonomic Rust API for tempo and transport control.
@@ -7,15 +66,65 @@ use ableton_link::{Clock, Link, SessionState};
 /// for setting tempo / playing state from application threads.
 pub struct LinkController {
     link: Link,
+    generation: u64,
+    known: Snapshot,
 }
 
 impl LinkController {
     /// Create a new Ableton Link controller with the provided initial tempo (in BPM).
     pub fn new(initial_bpm: f64) -> Self {
         let mut link = Link::new(initial_bpm);
+        link.set_num_peers_callback(on_num_peers);
+        link.set_tempo_callback(on_tempo);
+        link.set_start_stop_callback(on_start_stop);
         link.enable_start_stop_sync(true);
         link.enable(true);
-        Self { link }
+        let mut controller = Self {
+            link,
+            generation: GENERATION.load(Ordering::Acquire),
+            known: Snapshot {
+                tempo: initial_bpm,
+                playing: false,
+                peers: 0,
+            },
+        };
+        controller.known = controller.snapshot();
+        controller
+    }
+
+    /// Number of other Link peers in the session.
+    pub fn num_peers(&self) -> usize {
+        self.link.num_peers()
+    }
+
+    /// Session changes made by other peers since the last call.
+    ///
+    /// Changes made through this controller are not reported back. Cheap to
+    /// call from the event loop: the session is only inspected after one of
+    /// the Link callbacks has fired.
+    pub fn poll_events(&mut self) -> Vec<LinkEvent> {
+        let generation = GENERATION.load(Ordering::Acquire);
+        if generation == self.generation {
+            return Vec::new();
+        }
+        self.generation = generation;
+
+        let current = self.snapshot();
+        let events = self.known.changes(&current);
+        self.known = current;
+        events
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot {
+            peers: self.link.num_peers(),
+            ..self.known
+        };
+        self.with_session_state(|state| {
+            snapshot.tempo = state.tempo();
+            snapshot.playing = state.is_playing();
+        });
+        snapshot
     }
 
     /// Access the underlying link clock (in microseconds).
@@ -23,8 +132,10 @@ impl LinkController {
         self.link.clock()
     }
 
-    /// Set the transport tempo (in BPM) at the current clock time.
+    /// Set the transport tempo (in BPM) at the current clock time, clamped
+    /// to the app's tempo limits.
     pub fn set_tempo(&mut self, bpm: f64) {
+        let bpm = clamp_bpm(bpm);
         let now = self.link.clock().micros();
         let mut state_opt = None;
         self.link
@@ -33,6 +144,7 @@ impl LinkController {
             state.set_tempo(bpm, now);
             self.link.commit_app_session_state(state);
         }
+        self.known.tempo = bpm;
     }
 
     /// Toggle the Link playing state at the current clock time.
@@ -45,11 +157,142 @@ impl LinkController {
             state.set_is_playing(playing, now);
             self.link.commit_app_session_state(state);
         }
+        self.known.playing = playing;
+    }
+
+    /// Start playing now, with the session at `beat` (quantized to
+    /// `quantum` when other peers are connected). Returns when playing
+    /// starts.
+    pub fn start_at_beat(&mut self, beat: f64, quantum: f64) -> Instant {
+        let now = self.link.clock().micros();
+        let mut state_opt = None;
+        self.link
+            .with_app_session_state(|state| state_opt = Some(state));
+        if let Some(mut state) = state_opt {
+            state.set_is_playing_and_request_beat_at_time(true, now, beat, quantum);
+            self.link.commit_app_session_state(state);
+        }
+        self.known.playing = true;
+        self.instant_at(now)
+    }
+
+    /// Start playing on the next multiple of `quantum` beats, `count_in`
+    /// beats after it, with the start as beat 0 of a bar. Peers start at the
+    /// same moment. Returns when playing starts.
+    pub fn start_quantized(&mut self, quantum: f64, count_in: f64) -> Instant {
+        let now = self.link.clock().micros();
+        let mut at = now;
+        let mut state_opt = None;
+        self.link
+            .with_app_session_state(|state| state_opt = Some(state));
+        if let Some(mut state) = state_opt {
+            at = next_bar_time(&state, now, quantum, count_in);
+            state.set_is_playing(true, at);
+            state.request_beat_at_start_playing_time(0.0, quantum);
+            self.link.commit_app_session_state(state);
+        }
+        self.known.playing = true;
+        self.instant_at(at)
+    }
+
+    /// Stop playing on the next multiple of `quantum` beats. Returns when
+    /// playing stops.
+    pub fn stop_quantized(&mut self, quantum: f64) -> Instant {
+        let now = self.link.clock().micros();
+        let mut at = now;
+        let mut state_opt = None;
+        self.link
+            .with_app_session_state(|state| state_opt = Some(state));
+        if let Some(mut state) = state_opt {
+            at = next_bar_time(&state, now, quantum, 0.0);
+            state.set_is_playing(false, at);
+            self.link.commit_app_session_state(state);
+        }
+        self.known.playing = false;
+        self.instant_at(at)
+    }
+
+    /// Put `beat` at `at`, keeping the phase of other peers' sessions: with
+    /// peers connected, the beat is shifted by whole quanta only if that
+    /// keeps the session's bar phase.
+    pub fn request_beat_at(&mut self, beat: f64, at: Instant, quantum: f64) {
+        let micros = self.micros_at(at);
+        let mut state_opt = None;
+        self.link
+            .with_app_session_state(|state| state_opt = Some(state));
+        if let Some(mut state) = state_opt {
+            state.request_beat_at_time(beat, micros, quantum);
+            self.link.commit_app_session_state(state);
+        }
+    }
+
+    /// Put `beat` at `at`, moving the beat grid of the whole session,
+    /// including other peers.
+    pub fn force_beat_at(&mut self, beat: f64, at: Instant, quantum: f64) {
+        let micros = self.micros_at(at);
+        let mut state_opt = None;
+        self.link
+            .with_app_session_state(|state| state_opt = Some(state));
+        if let Some(mut state) = state_opt {
+            state.force_beat_at_time(beat, micros, quantum);
+            self.link.commit_app_session_state(state);
+        }
+    }
+
+    /// Move the session's beat grid `beats` ahead (or back, if negative)
+    /// without changing the tempo. Other peers follow the shift.
+    pub fn shift_phase(&mut self, beats: f64, quantum: f64) {
+        let now = self.link.clock().micros();
+        let mut state_opt = None;
+        self.link
+            .with_app_session_state(|state| state_opt = Some(state));
+        if let Some(mut state) = state_opt {
+            let beat = state.beat_at_time(now, quantum);
+            state.force_beat_at_time(beat + beats, now, quantum);
+            self.link.commit_app_session_state(state);
+        }
+    }
+
+    /// Make the current moment the first beat of a bar, moving the grid to
+    /// the nearest bar line. Returns how many beats the grid moved.
+    pub fn resync(&mut self, quantum: f64) -> f64 {
+        let now = self.link.clock().micros();
+        let mut shift = 0.0;
+        self.with_session_state(|state| {
+            let beat = state.beat_at_time(now, quantum);
+            shift = (beat / quantum).round() * quantum - beat;
+        });
+        self.shift_phase(shift, quantum);
+        shift
+    }
+
+    /// Link clock time of an [`Instant`].
+    fn micros_at(&self, at: Instant) -> i64 {
+        let now = Instant::now();
+        let micros = self.link.clock().micros();
+        if at >= now {
+            micros + (at - now).as_micros() as i64
+        } else {
+            micros - (now - at).as_micros() as i64
+        }
+    }
+
+    /// [`Instant`] of a Link clock time.
+    fn instant_at(&self, micros: i64) -> Instant {
+        let now = Instant::now();
+        let ahead = micros - self.link.clock().micros();
+        let offset = Duration::from_micros(ahead.unsigned_abs());
+        let at = if ahead >= 0 {
+            now.checked_add(offset)
+        } else {
+            now.checked_sub(offset)
+        };
+        at.unwrap_or(now)
     }
 
     /// Atomically set both tempo and playing state.
-    #[allow(dead_code)]
     pub fn set_tempo_and_playing(&mut self, bpm: f64, playing: bool) {
+        let bpm = clamp_bpm(bpm);
         let now = self.link.clock().micros();
         let mut state_opt = None;
         self.link
@@ -59,6 +302,22 @@ impl LinkController {
             state.set_is_playing(playing, now);
             self.link.commit_app_session_state(state);
         }
+        self.known.tempo = bpm;
+        self.known.playing = playing;
+    }
+
+    /// Snapshot of the session's beat timeline, anchored at the current time.
+    ///
+    /// The snapshot is only valid until the session tempo or beat grid
+    /// changes, so take a fresh one whenever it is handed to another thread.
+    pub fn timeline(&self, quantum: f64) -> Timeline {
+        let at = Instant::now();
+        let micros = self.link.clock().micros();
+        let mut timeline = Timeline::new(0.0, 0.0, at);
+        self.with_session_state(|state| {
+            timeline = Timeline::new(state.tempo(), state.beat_at_time(micros, quantum), at);
+        });
+        timeline
     }
 
     /// Inspect the current session state via a closure.
@@ -74,3 +333,46 @@ impl LinkController {
         }
     }
 }
+
+/// Link clock time of the first bar line at or after `now`, plus `extra`
+/// beats.
+fn next_bar_time(state: &SessionState, now: i64, quantum: f64, extra: f64) -> i64 {
+    let quantum = quantum.max(f64::EPSILON);
+    let bar = (state.beat_at_time(now, quantum) / quantum).ceil() * quantum;
+    state.time_at_beat(bar + extra, quantum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_only_what_changed() {
+        let before = Snapshot {
+            tempo: 120.0,
+            playing: false,
+            peers: 1,
+        };
+        assert!(before.changes(&before).is_empty());
+
+        let rounded = Snapshot {
+            tempo: 120.0001,
+            ..before
+        };
+        assert!(before.changes(&rounded).is_empty());
+
+        let after = Snapshot {
+            tempo: 128.0,
+            playing: true,
+            peers: 2,
+        };
+        assert_eq!(
+            before.changes(&after),
+            vec![
+                LinkEvent::Tempo(128.0),
+                LinkEvent::Playing(true),
+                LinkEvent::Peers(2)
+            ]
+        );
+    }
+}
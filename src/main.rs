@@ -1,29 +1,85 @@
-mod link_controller;
-mod midi_clock;
-mod tap_tempo;
-mod x1_controller;
-
 use std::{
     env,
+    error::Error,
+    path::Path,
     sync::mpsc,
     thread,
     time::{Duration, Instant},
 };
 
-use link_controller::LinkController;
-use midi_clock::MidiClock;
-use tap_tempo::TapTempo;
-use x1_controller::{
-    ButtonEvent, ButtonEventKind, ButtonId, Timestamp, X1Controller, LED_BRIGHT, LED_DIM,
+use x1_tap_clock::link_controller::{LinkController, LinkEvent};
+use x1_tap_clock::mapping::{Action, Mapping};
+use x1_tap_clock::midi_clock::{ClockOutputConfig, MidiClock, MidiClockError, PortSelector};
+use x1_tap_clock::midi_clock_input::{ClockInputEvent, MidiClockInput};
+use x1_tap_clock::midi_surface::{MidiFeedback, MidiSurface};
+use x1_tap_clock::tap_tempo::{TapEstimate, TapTempo};
+use x1_tap_clock::tempo::clamp_bpm;
+use x1_tap_clock::timeline::Timeline;
+use x1_tap_clock::x1_controller::{
+    read_capture, ButtonEvent, ButtonEventKind, ButtonId, CaptureTransport, CaptureWriter,
+    ConnectionEvent, Effect, EncoderEvent, Layer, LedCompositor, LedId, PotEvent, ReplayTransport,
+    Timebase, Timestamp, UsbTransport, X1Controller, X1Transport, LED_BRIGHT,
 };
 
 const START_BPM: f64 = 120.0;
-const TAP_LED_INDEX: usize = 23;
-const FLASH_DURATION_MS: u64 = 160;
+const FLASH_DURATION: Duration = Duration::from_millis(160);
 const LED_MEDIUM: u8 = 0x30;
 const QUANTUM_BEATS: f64 = 4.0;
 const DOWNBEAT_WINDOW: f64 = 0.12;
 const BEAT_WINDOW: f64 = 0.08;
+/// Lit share of each beat while a quantized start or stop counts down.
+const COUNTDOWN_DUTY: f64 = 0.5;
+const DEFAULT_MAPPING_FILE: &str = "board.yml";
+/// Drift in beats after which a new Link timeline is sent to the MIDI clock.
+const TIMELINE_TOLERANCE: f64 = 0.001;
+
+type Controller = X1Controller<Box<dyn X1Transport>>;
+
+/// Command line options.
+///
+/// `x1-tap-clock [--mapping FILE] [--free-clock] [--clock-in PORT [--tap-override]]
+///  [--midi-surface] [--capture FILE | --replay FILE [--paced]] [midi-port-hint]`
+#[derive(Debug, Default)]
+struct Options {
+    midi_port_hint: String,
+    mapping: Option<String>,
+    capture: Option<String>,
+    replay: Option<String>,
+    paced: bool,
+    free_clock: bool,
+    clock_in: Option<String>,
+    tap_override: bool,
+    midi_surface: bool,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--mapping" => {
+                    options.mapping = Some(args.next().ok_or("--mapping needs a file")?);
+                }
+                "--capture" => {
+                    options.capture = Some(args.next().ok_or("--capture needs a file")?);
+                }
+                "--replay" => {
+                    options.replay = Some(args.next().ok_or("--replay needs a file")?);
+                }
+                "--paced" => options.paced = true,
+                "--free-clock" => options.free_clock = true,
+                "--clock-in" => {
+                    options.clock_in = Some(args.next().ok_or("--clock-in needs a port")?);
+                }
+                "--tap-override" => options.tap_override = true,
+                "--midi-surface" => options.midi_surface = true,
+                flag if flag.starts_with("--") => return Err(format!("unknown option {flag}")),
+                _ => options.midi_port_hint = arg,
+            }
+        }
+        Ok(options)
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 enum ControlMessage {
@@ -31,50 +87,91 @@ enum ControlMessage {
         event: ButtonEvent,
         timestamp: Timestamp,
     },
+    Encoder {
+        event: EncoderEvent,
+    },
+    Pot {
+        event: PotEvent,
+    },
+    Connection(ConnectionEvent),
 }
 
-fn main() -> rusb::Result<()> {
-    let mut controller = X1Controller::connect()?;
-    let midi_port_hint = env::args().nth(1).unwrap_or_default();
+fn main() -> Result<(), Box<dyn Error>> {
+    let options = Options::parse(env::args().skip(1))?;
+    if let Some(path) = options.replay.as_deref() {
+        return run_replay(path, options.paced);
+    }
 
-    // Ensure the tap LED starts dimmed.
-    controller.set_led_raw(TAP_LED_INDEX, LED_DIM);
+    let mut mapping = load_mapping(options.mapping.as_deref())?;
+    let mut controller = connect_controller(options.capture.as_deref())?;
+    let midi_port_hint = options.midi_port_hint;
 
     let (tx, rx) = mpsc::channel::<ControlMessage>();
+    let connection_tx = tx.clone();
+    controller.set_connection_callback(move |event, _| {
+        let _ = connection_tx.send(ControlMessage::Connection(event));
+    });
+    let encoder_tx = tx.clone();
+    controller.set_encoder_callback(move |_, event, _, _| {
+        let _ = encoder_tx.send(ControlMessage::Encoder { event });
+    });
+    let pot_tx = tx.clone();
+    controller.set_pot_callback(move |_, event, _, _| {
+        let _ = pot_tx.send(ControlMessage::Pot { event });
+    });
     controller.set_button_callback(move |_, event, timestamp, _handle| {
-        if matches!(event.kind, ButtonEventKind::Pressed) {
-            let _ = tx.send(ControlMessage::Button { event, timestamp });
-        }
+        let _ = tx.send(ControlMessage::Button { event, timestamp });
     });
 
-    let midi_clock = match MidiClock::new(&midi_port_hint, START_BPM) {
-        Ok(clock) => {
-            println!("MIDI clock connected to {}", clock.port_name());
-            Some(clock)
-        }
-        Err(err) => {
-            if midi_port_hint.is_empty() {
-                eprintln!(
-                    "Warning: unable to open MIDI clock output; continuing without MIDI clock ({err})"
-                );
-            } else {
+    let midi_clocks = open_clock_outputs(&midi_port_hint, &mapping.clock_outputs);
+
+    let clock_input = options.clock_in.as_deref().and_then(|hint| {
+        match MidiClockInput::new(hint) {
+            Ok(input) => {
+                println!("Following MIDI clock from {}", input.port_name());
+                Some(input)
+            }
+            Err(err) => {
                 eprintln!(
-                    "Warning: unable to open MIDI clock output for hint '{midi_port_hint}'; continuing without MIDI clock ({err})"
+                    "Warning: unable to open MIDI clock input for hint '{hint}'; continuing without it ({err})"
                 );
+                None
             }
-            None
         }
-    };
-
-    let mut link = LinkController::new(START_BPM);
-    let mut tapper = TapTempo::new(4, 2.0);
-    let mut playing = false;
-    let mut current_bpm: Option<f64> = Some(START_BPM);
+    });
 
-    let mut flash_until: Option<Instant> = None;
-    let mut current_led_value: u8 = LED_DIM;
+    if options.midi_surface && mapping.midi_surface.is_none() {
+        mapping.midi_surface = Some(Default::default());
+    }
+    let midi_feedback = mapping.midi_surface.clone().and_then(|config| {
+        MidiFeedback::open(config)
+            .map_err(|err| {
+                eprintln!("Warning: unable to create the MIDI feedback port; LEDs ignore MIDI input ({err})");
+            })
+            .ok()
+    });
+    let midi_surface = mapping.midi_surface.clone().and_then(|config| {
+        match MidiSurface::open(config) {
+            Ok(surface) => {
+                println!("MIDI surface on virtual port {}", surface.port_name());
+                Some(surface)
+            }
+            Err(err) => {
+                eprintln!("Warning: unable to create the MIDI surface port; continuing without it ({err})");
+                None
+            }
+        }
+    });
 
-    let app_start = Instant::now();
+    let mut app = App::new(
+        mapping,
+        midi_clocks,
+        clock_input,
+        midi_surface,
+        midi_feedback,
+        options.free_clock,
+        options.tap_override,
+    );
 
     loop {
         controller.poll_once()?;
@@ -83,150 +180,767 @@ fn main() -> rusb::Result<()> {
         while let Ok(message) = rx.try_recv() {
             match message {
                 ControlMessage::Button { event, timestamp } => {
-                    handle_button_event(
-                        event,
-                        timestamp,
-                        &app_start,
-                        &mut tapper,
-                        &mut link,
-                        &mut controller,
-                        &mut playing,
-                        &mut current_bpm,
-                        &midi_clock,
-                        &mut flash_until,
-                        &mut current_led_value,
-                    );
-                }
-            }
-        }
-
-        update_led_feedback(
-            &mut link,
-            &mut controller,
-            &mut flash_until,
-            playing,
-            &mut current_led_value,
-        );
+                    app.handle_button_event(event, timestamp);
+                }
+                ControlMessage::Encoder { event } => app.handle_encoder_event(event),
+                ControlMessage::Pot { event } => app.handle_pot_event(event),
+                ControlMessage::Connection(ConnectionEvent::Connected) => {
+                    println!("X1 controller connected");
+                }
+                ControlMessage::Connection(ConnectionEvent::Disconnected) => {
+                    println!("X1 controller disconnected; clock keeps running");
+                    app.handle_disconnect();
+                }
+            }
+        }
+
+        app.sync_clock_input();
+        app.sync_link();
+        app.sync_clock();
+        app.update_armed();
+        app.update_led_feedback(&mut controller);
 
         thread::sleep(Duration::from_millis(2));
     }
 }
 
-#[allow(clippy::too_many_arguments)]
-fn handle_button_event(
-    event: ButtonEvent,
-    timestamp: Timestamp,
-    app_start: &Instant,
-    tapper: &mut TapTempo,
-    link: &mut LinkController,
-    controller: &mut X1Controller,
-    playing: &mut bool,
-    current_bpm: &mut Option<f64>,
-    midi_clock: &Option<MidiClock>,
-    flash_until: &mut Option<Instant>,
-    current_led_value: &mut u8,
-) {
-    match event.id {
-        ButtonId::Deck1Sync if event.modifiers.shift => {
-            let tap_time = timestamp
-                .checked_duration_since(*app_start)
-                .map(|d| d.as_secs_f64())
-                .unwrap_or(0.0);
-
-            if let Some(bpm) = tapper.add_tap(tap_time) {
-                link.set_tempo(bpm);
-                if let Some(clock) = midi_clock.as_ref() {
-                    let _ = clock.set_bpm(bpm);
-                }
-                if !*playing {
-                    link.set_playing(true);
-                    if let Some(clock) = midi_clock.as_ref() {
-                        let _ = clock.start();
-                    }
-                    *playing = true;
-                    println!("Clock START @ {:.2} BPM", bpm);
-                } else {
-                    println!("Tempo set to {:.2} BPM", bpm);
-                }
-                *current_bpm = Some(bpm);
+/// Load the mapping given on the command line, else `board.yml` from the
+/// working directory if present, else the built-in layout.
+fn load_mapping(path: Option<&str>) -> Result<Mapping, Box<dyn Error>> {
+    let path = match path {
+        Some(path) => path,
+        None if Path::new(DEFAULT_MAPPING_FILE).exists() => DEFAULT_MAPPING_FILE,
+        None => return Ok(Mapping::builtin()),
+    };
+    let mapping = Mapping::load(path).map_err(|err| format!("{path}: {err}"))?;
+    println!("Loaded mapping from {path}");
+    Ok(mapping)
+}
+
+/// Open the MIDI clock outputs: the port given on the command line if any,
+/// else the `clock_outputs` of the mapping, else the first port. Outputs that
+/// fail to open are skipped with a warning.
+fn open_clock_outputs(port_hint: &str, configured: &[ClockOutputConfig]) -> Vec<MidiClock> {
+    let from_hint;
+    let configs = if port_hint.is_empty() && !configured.is_empty() {
+        configured
+    } else {
+        from_hint = [ClockOutputConfig::new(PortSelector::Name(port_hint.into()))];
+        &from_hint[..]
+    };
+
+    configs
+        .iter()
+        .filter(|config| config.enabled)
+        .filter_map(|config| match MidiClock::open(config, START_BPM) {
+            Ok(clock) => {
+                println!("MIDI clock connected to {}", clock.port_name());
+                Some(clock)
+            }
+            Err(err) => {
+                eprintln!(
+                    "Warning: unable to open MIDI clock output '{}'; continuing without it ({err})",
+                    config.port
+                );
+                None
             }
+        })
+        .collect()
+}
 
-            *flash_until = Some(Instant::now() + Duration::from_millis(FLASH_DURATION_MS));
-            controller.set_led_raw(TAP_LED_INDEX, LED_BRIGHT);
-            *current_led_value = LED_BRIGHT;
+/// Open a supervised USB controller, optionally logging all traffic to a capture file.
+fn connect_controller(capture: Option<&str>) -> Result<Controller, Box<dyn Error>> {
+    let context = rusb::Context::new()?;
+    let writer = match capture {
+        Some(path) => {
+            println!("Capturing USB traffic to {path}");
+            Some(CaptureWriter::create(path)?)
         }
-        ButtonId::Deck1Sync => {
+        None => None,
+    };
+
+    let controller = X1Controller::supervised(move || {
+        let transport = UsbTransport::open(&context)?;
+        Ok(transport.map(|usb| match &writer {
+            Some(writer) => Box::new(CaptureTransport::new(usb, writer.clone())) as Box<_>,
+            None => Box::new(usb) as Box<dyn X1Transport>,
+        }))
+    });
+    if !controller.is_connected() {
+        eprintln!("No X1 controller found; waiting for one to be plugged in.");
+    }
+    Ok(controller)
+}
+
+/// Feed a capture file through the controller and print every decoded event.
+fn run_replay(path: &str, paced: bool) -> Result<(), Box<dyn Error>> {
+    let records = read_capture(path)?;
+    let mut controller = X1Controller::with_transport(ReplayTransport::new(records, paced));
+    let start = Instant::now();
+
+    controller.set_button_callback(move |_, event, timestamp, _| {
+        let at = timestamp.duration_since(start).as_secs_f64();
+        println!(
+            "{at:9.3}s button  {:?} {:?} {:?}",
+            event.id, event.kind, event.modifiers
+        );
+    });
+    controller.set_encoder_callback(move |_, event, timestamp, _| {
+        let at = timestamp.duration_since(start).as_secs_f64();
+        println!(
+            "{at:9.3}s encoder {:?} {} -> {} ({:+})",
+            event.id, event.previous, event.value, event.delta
+        );
+    });
+    controller.set_pot_callback(move |_, event, timestamp, _| {
+        let at = timestamp.duration_since(start).as_secs_f64();
+        println!(
+            "{at:9.3}s pot     {:?} {} -> {} (raw {}, {:.3})",
+            event.id, event.previous, event.value, event.raw, event.normalized
+        );
+    });
+
+    while !controller
+        .transport()
+        .is_some_and(ReplayTransport::is_finished)
+    {
+        controller.poll_once()?;
+    }
+    Ok(())
+}
+
+/// A held pitch-bend button.
+#[derive(Debug, Clone, Copy)]
+struct Bend {
+    button: ButtonId,
+    percent: f64,
+    led: Option<LedId>,
+}
+
+/// A quantized start or stop waiting for its bar line.
+#[derive(Debug, Clone, Copy)]
+struct Armed {
+    playing: bool,
+    at: Instant,
+}
+
+/// Application state driven by mapped controller events.
+struct App {
+    mapping: Mapping,
+    link: LinkController,
+    midi_clocks: Vec<MidiClock>,
+    /// Run the MIDI clock on its own tempo instead of the Link timeline.
+    free_clock: bool,
+    clock_timeline: Option<Timeline>,
+    clock_input: Option<MidiClockInput>,
+    /// Virtual MIDI port mirroring every control as notes and CCs.
+    midi_surface: Option<MidiSurface>,
+    /// Virtual MIDI input whose notes and CCs light the button LEDs.
+    midi_feedback: Option<MidiFeedback>,
+    /// Let tap tempo take over from a locked external clock.
+    tap_override: bool,
+    /// Tap tempo has taken over; external tempo is ignored until the
+    /// external clock restarts or is lost.
+    clock_overridden: bool,
+    tapper: TapTempo,
+    playing: bool,
+    /// Stopped with a song position to continue from.
+    paused: bool,
+    /// The tempo set by taps and tempo actions, without any bend.
+    current_bpm: f64,
+    bend: Option<Bend>,
+    /// Quantized start or stop that has not happened yet; `playing` already
+    /// shows where the transport is headed.
+    armed: Option<Armed>,
+    app_start: Instant,
+    leds: LedCompositor,
+}
+
+impl App {
+    fn new(
+        mapping: Mapping,
+        midi_clocks: Vec<MidiClock>,
+        clock_input: Option<MidiClockInput>,
+        midi_surface: Option<MidiSurface>,
+        midi_feedback: Option<MidiFeedback>,
+        free_clock: bool,
+        tap_override: bool,
+    ) -> Self {
+        let tapper = TapTempo::from_config(&mapping.tap_tempo);
+        let mut app = Self {
+            mapping,
+            link: LinkController::new(START_BPM),
+            midi_clocks,
+            free_clock,
+            clock_timeline: None,
+            clock_input,
+            midi_surface,
+            midi_feedback,
+            tap_override,
+            clock_overridden: false,
+            tapper,
+            playing: false,
+            paused: false,
+            current_bpm: START_BPM,
+            bend: None,
+            armed: None,
+            app_start: Instant::now(),
+            leds: LedCompositor::new(),
+        };
+        app.sync_clock();
+        app
+    }
+
+    fn handle_button_event(&mut self, event: ButtonEvent, timestamp: Timestamp) {
+        if let Some(surface) = self.midi_surface.as_mut() {
+            surface.send_button(&event);
+        }
+        if event.kind != ButtonEventKind::Pressed {
+            if self.bend.is_some_and(|bend| bend.button == event.id) {
+                self.end_bend();
+            }
             return;
         }
-        ButtonId::Deck1Play => {
-            *playing = !*playing;
-            link.set_playing(*playing);
-            if *playing {
-                if let Some(clock) = midi_clock.as_ref() {
-                    let target_bpm = current_bpm.unwrap_or(START_BPM);
-                    let _ = clock.set_bpm(target_bpm);
-                    let _ = clock.start();
-                }
-                println!("Clock START @ {:.2} BPM", current_bpm.unwrap_or(START_BPM));
-                *flash_until = Some(Instant::now() + Duration::from_millis(FLASH_DURATION_MS));
-                controller.set_led_raw(TAP_LED_INDEX, LED_BRIGHT);
-                *current_led_value = LED_BRIGHT;
-            } else {
-                if let Some(clock) = midi_clock.as_ref() {
-                    let _ = clock.stop();
+        let Some(binding) = self.mapping.button(event.id, event.modifiers).cloned() else {
+            return;
+        };
+        if let Action::Bend(percent) = binding.action {
+            self.start_bend(Bend {
+                button: event.id,
+                percent,
+                led: binding.led,
+            });
+            return;
+        }
+        self.perform(binding.action, 1.0, timestamp, binding.led);
+    }
+
+    /// Nothing can be held on a controller that is gone; a bend would
+    /// otherwise never end.
+    fn handle_disconnect(&mut self) {
+        if self.bend.is_some() {
+            self.end_bend();
+        }
+    }
+
+    fn handle_encoder_event(&mut self, event: EncoderEvent) {
+        if let Some(surface) = self.midi_surface.as_mut() {
+            surface.send_encoder(&event);
+        }
+        let Some(binding) = self.mapping.encoder(event.id, event.modifiers).cloned() else {
+            return;
+        };
+        self.perform(
+            binding.action,
+            f64::from(event.accelerated_delta),
+            Instant::now(),
+            binding.led,
+        );
+    }
+
+    fn handle_pot_event(&mut self, event: PotEvent) {
+        if let Some(surface) = self.midi_surface.as_mut() {
+            surface.send_pot(&event);
+        }
+        let Some(binding) = self.mapping.pot(event.id, event.modifiers).cloned() else {
+            return;
+        };
+        self.perform(
+            binding.action,
+            f64::from(event.normalized),
+            Instant::now(),
+            binding.led,
+        );
+    }
+
+    /// Run an action. `amount` is the detent count for encoders, the
+    /// normalized position for pots, and 1.0 for buttons.
+    fn perform(&mut self, action: Action, amount: f64, timestamp: Timestamp, led: Option<LedId>) {
+        match action {
+            Action::Tap => self.tap(timestamp),
+            Action::Start => {
+                if !self.playing {
+                    self.start();
+                }
+            }
+            Action::Stop => {
+                if self.playing || self.paused {
+                    self.stop(led);
+                    return;
+                }
+            }
+            Action::Toggle => {
+                if self.playing {
+                    self.stop(led);
+                    return;
+                }
+                self.start();
+            }
+            Action::Pause => {
+                if self.playing {
+                    self.pause(led);
+                    return;
+                }
+                self.resume();
+            }
+            Action::Locate(beat) => {
+                self.each_clock(|clock| clock.locate(beat));
+                println!("Song position set to beat {beat}");
+            }
+            Action::Nudge(bpm) => {
+                self.set_tempo(self.current_bpm + bpm * amount);
+                println!("Tempo set to {:.2} BPM", self.current_bpm);
+            }
+            Action::PhaseMs(ms) => {
+                self.shift_phase(ms * amount / 1000.0 * self.current_bpm / 60.0);
+            }
+            Action::PhaseBeats(beats) => self.shift_phase(beats * amount),
+            Action::Resync => {
+                let shift = self.link.resync(QUANTUM_BEATS);
+                self.follow_phase_shift(shift);
+                println!("Resynced: beat 1 is now");
+            }
+            // Needs the release as well, see `handle_button_event`.
+            Action::Bend(_) => {}
+            Action::SetBpm(bpm) => {
+                self.set_tempo(bpm);
+                println!("Tempo set to {:.2} BPM", self.current_bpm);
+            }
+            Action::HalfTempo => {
+                self.set_tempo(self.current_bpm / 2.0);
+                println!("Tempo halved to {:.2} BPM", self.current_bpm);
+            }
+            Action::DoubleTempo => {
+                self.set_tempo(self.current_bpm * 2.0);
+                println!("Tempo doubled to {:.2} BPM", self.current_bpm);
+            }
+            Action::BpmRange { min, max } => {
+                self.set_tempo(min + (max - min) * amount.clamp(0.0, 1.0));
+            }
+        }
+
+        if let Some(led) = led {
+            self.leds.flash(led, LED_BRIGHT, FLASH_DURATION);
+        }
+    }
+
+    fn tap(&mut self, timestamp: Timestamp) {
+        let tap_time = timestamp
+            .checked_duration_since(self.app_start)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0);
+
+        if let Some(estimate) = self.tapper.add_tap_estimate(tap_time) {
+            let bpm = estimate.bpm;
+            if self.external_clock_locked() {
+                if !self.tap_override {
+                    println!("External MIDI clock is locked; ignoring tap tempo");
+                    return;
                 }
-                println!("Clock STOP");
-                *flash_until = None;
-                controller.set_led_raw(TAP_LED_INDEX, LED_DIM);
-                *current_led_value = LED_DIM;
+                if !self.clock_overridden {
+                    println!("Tap tempo overrides the external MIDI clock");
+                    self.clock_overridden = true;
+                }
+            }
+            self.set_tempo(bpm);
+            self.align_tap_phase(&estimate);
+            if !self.playing {
+                self.start();
+            } else {
+                println!(
+                    "Tempo set to {:.2} BPM (confidence {:.0}%)",
+                    bpm,
+                    estimate.confidence * 100.0
+                );
             }
         }
-        _ => return,
     }
-}
 
-fn update_led_feedback(
-    link: &mut LinkController,
-    controller: &mut X1Controller,
-    flash_until: &mut Option<Instant>,
-    playing: bool,
-    current_led_value: &mut u8,
-) {
-    let now = Instant::now();
-    let flash_active = if let Some(deadline) = flash_until {
-        if now < *deadline {
-            true
+    /// Move the Link beat grid so the last tap lands on a beat (or on its
+    /// place in the bar, if the first tap was a downbeat).
+    fn align_tap_phase(&mut self, estimate: &TapEstimate) {
+        let Some(at) = Duration::try_from_secs_f64(estimate.last_beat)
+            .ok()
+            .and_then(|offset| self.app_start.checked_add(offset))
+        else {
+            return;
+        };
+        let config = &self.mapping.tap_tempo;
+        let downbeat = config.first_tap_downbeat.then_some(QUANTUM_BEATS);
+        let current = self.link.timeline(QUANTUM_BEATS).beat_at(at);
+        let beat = estimate.aligned_beat(current, downbeat);
+        if config.force_phase {
+            self.link.force_beat_at(beat, at, QUANTUM_BEATS);
         } else {
-            *flash_until = None;
-            false
+            self.link.request_beat_at(beat, at, QUANTUM_BEATS);
         }
-    } else {
-        false
-    };
+        self.sync_clock();
+    }
+
+    fn start(&mut self) {
+        let transport = self.mapping.transport;
+        if !transport.quantized {
+            self.link.set_playing(true);
+            self.start_playback(None);
+            return;
+        }
+
+        if self
+            .armed
+            .is_some_and(|armed| !armed.playing && armed.at > Instant::now())
+        {
+            self.cancel_stop();
+            return;
+        }
+        self.cancel_armed();
+        let count_in = f64::from(transport.count_in) * QUANTUM_BEATS;
+        let at = self.link.start_quantized(QUANTUM_BEATS, count_in);
+        self.paused = false;
+        self.prepare_playback();
+        self.each_clock(|clock| clock.start_at(at));
+        self.arm(true, at);
+        println!(
+            "Clock START in {:.2} s @ {:.2} BPM",
+            at.saturating_duration_since(Instant::now()).as_secs_f64(),
+            self.current_bpm
+        );
+    }
+
+    fn stop(&mut self, led: Option<LedId>) {
+        // Stopping during a count-in or while paused cancels right away.
+        let counting_in = self.armed.is_some_and(|armed| armed.playing);
+        if !self.mapping.transport.quantized || counting_in || !self.playing {
+            self.link.set_playing(false);
+            self.stop_playback(led);
+            return;
+        }
+
+        let at = self.link.stop_quantized(QUANTUM_BEATS);
+        self.playing = false;
+        self.each_clock(|clock| clock.stop_at(at));
+        self.arm(false, at);
+        println!(
+            "Clock STOP in {:.2} s",
+            at.saturating_duration_since(Instant::now()).as_secs_f64()
+        );
+    }
+
+    /// Keep playing after all: take back a pending quantized stop, so
+    /// followers play on without a gap.
+    fn cancel_stop(&mut self) {
+        self.armed = None;
+        if let Some(beat_led) = self.mapping.beat_led {
+            self.leds.clear(Layer::Overlay, beat_led);
+        }
+        self.link.set_playing(true);
+        self.each_clock(|clock| clock.cancel_stop());
+        self.playing = true;
+        println!("Clock STOP cancelled");
+    }
+
+    fn pause(&mut self, led: Option<LedId>) {
+        self.link.set_playing(false);
+        self.pause_playback(led);
+    }
+
+    fn resume(&mut self) {
+        self.link.set_playing(true);
+        self.resume_playback();
+    }
+
+    /// Count down to a quantized start or stop on the beat LED.
+    fn arm(&mut self, playing: bool, at: Instant) {
+        if let Some(beat_led) = self.mapping.beat_led {
+            let countdown = Effect::Blink {
+                on: LED_BRIGHT,
+                off: Some(0),
+                period: 1.0,
+                duty: COUNTDOWN_DUTY,
+            };
+            self.leds.set_for(
+                Layer::Overlay,
+                beat_led,
+                countdown,
+                Timebase::Beats,
+                at.saturating_duration_since(Instant::now()),
+            );
+        }
+        self.armed = Some(Armed { playing, at });
+    }
+
+    /// Forget a pending quantized start or stop; a pending stop happens
+    /// right away instead.
+    fn cancel_armed(&mut self) {
+        let Some(armed) = self.armed.take() else {
+            return;
+        };
+        if let Some(beat_led) = self.mapping.beat_led {
+            self.leds.clear(Layer::Overlay, beat_led);
+        }
+        if !armed.playing {
+            self.each_clock(|clock| clock.stop());
+            self.leds.clear_layer(Layer::Beat);
+        }
+    }
 
-    let mut desired_led = if flash_active { LED_BRIGHT } else { LED_DIM };
+    /// Finish a quantized start or stop once its bar line has passed; the
+    /// MIDI clocks and Link peers have acted on it already.
+    fn update_armed(&mut self) {
+        let Some(armed) = self.armed.filter(|armed| armed.at <= Instant::now()) else {
+            return;
+        };
+        self.armed = None;
+        if armed.playing {
+            println!("Clock started");
+        } else {
+            self.leds.clear_layer(Layer::Beat);
+            println!("Clock stopped");
+        }
+    }
 
-    if !flash_active && playing {
-        let now_micros = link.clock().micros();
-        let mut phase_opt = None;
-        link.with_session_state(|state| {
-            phase_opt = Some(state.phase_at_time(now_micros, QUANTUM_BEATS));
+    /// Start the MIDI clock and beat LED without touching the Link transport,
+    /// at `at` if given or else on the next bar.
+    fn start_playback(&mut self, at: Option<Instant>) {
+        self.cancel_armed();
+        self.paused = false;
+        self.prepare_playback();
+        self.each_clock(|clock| match at {
+            Some(at) => clock.start_at(at),
+            None => clock.start(),
         });
+        println!("Clock START @ {:.2} BPM", self.current_bpm);
+    }
 
-        if let Some(phase) = phase_opt {
-            let beat_phase = phase.fract();
-            desired_led = if phase < DOWNBEAT_WINDOW {
-                LED_BRIGHT
-            } else if beat_phase < BEAT_WINDOW {
-                LED_MEDIUM
-            } else {
-                LED_DIM
+    /// Continue the MIDI clock from the paused song position.
+    fn resume_playback(&mut self) {
+        self.cancel_armed();
+        self.paused = false;
+        self.prepare_playback();
+        self.each_clock(|clock| clock.resume());
+        println!("Clock CONTINUE @ {:.2} BPM", self.current_bpm);
+    }
+
+    fn prepare_playback(&mut self) {
+        self.playing = true;
+        self.sync_clock();
+        self.set_free_clock_bpm(self.current_bpm);
+
+        if let Some(beat_led) = self.mapping.beat_led {
+            let metronome = Effect::Metronome {
+                accent: LED_BRIGHT,
+                beat: LED_MEDIUM,
+                quantum: QUANTUM_BEATS,
+                accent_width: DOWNBEAT_WINDOW,
+                beat_width: BEAT_WINDOW,
             };
+            self.leds
+                .set(Layer::Beat, beat_led, metronome, Timebase::Beats);
         }
     }
 
-    if desired_led != *current_led_value {
-        controller.set_led_raw(TAP_LED_INDEX, desired_led);
-        *current_led_value = desired_led;
+    /// Stop the MIDI clock and beat LED without touching the Link transport.
+    fn stop_playback(&mut self, led: Option<LedId>) {
+        self.cancel_armed();
+        self.paused = false;
+        self.each_clock(|clock| clock.stop());
+        println!("Clock STOP");
+        self.clear_playback(led);
+    }
+
+    /// Stop the MIDI clock but keep its song position for [`Self::resume_playback`].
+    fn pause_playback(&mut self, led: Option<LedId>) {
+        self.cancel_armed();
+        self.paused = true;
+        self.each_clock(|clock| clock.pause());
+        println!("Clock PAUSE");
+        self.clear_playback(led);
+    }
+
+    fn clear_playback(&mut self, led: Option<LedId>) {
+        self.playing = false;
+        if let Some(led) = led {
+            self.leds.clear(Layer::Flash, led);
+        }
+        self.leds.clear_layer(Layer::Beat);
+    }
+
+    /// Set the tempo that taps, encoders and tempo actions work from. A
+    /// held bend stays on top of it.
+    fn set_tempo(&mut self, bpm: f64) {
+        self.current_bpm = clamp_bpm(bpm);
+        self.apply_tempo();
+    }
+
+    /// Send the set tempo, bent if a bend button is held, to Link and the
+    /// MIDI clocks.
+    fn apply_tempo(&mut self) {
+        let bpm = match self.bend {
+            Some(bend) => clamp_bpm(self.current_bpm * (1.0 + bend.percent / 100.0)),
+            None => self.current_bpm,
+        };
+        self.link.set_tempo(bpm);
+        self.set_free_clock_bpm(bpm);
+        self.sync_clock();
+    }
+
+    fn start_bend(&mut self, bend: Bend) {
+        if let Some(led) = bend.led {
+            self.leds
+                .set(Layer::Flash, led, Effect::Solid(LED_BRIGHT), Timebase::Wall);
+        }
+        self.bend = Some(bend);
+        self.apply_tempo();
+    }
+
+    /// Return to the set tempo, keeping the phase the bend moved to.
+    fn end_bend(&mut self) {
+        if let Some(led) = self.bend.take().and_then(|bend| bend.led) {
+            self.leds.clear(Layer::Flash, led);
+        }
+        self.apply_tempo();
+    }
+
+    fn shift_phase(&mut self, beats: f64) {
+        self.link.shift_phase(beats, QUANTUM_BEATS);
+        self.follow_phase_shift(beats);
+        println!("Phase shifted by {beats:+.3} beats");
+    }
+
+    /// Move the MIDI clocks' grid along with a phase shift of the Link
+    /// session, keeping followers in phase.
+    fn follow_phase_shift(&mut self, beats: f64) {
+        self.each_clock(|clock| clock.shift_phase(beats));
+        if !self.free_clock {
+            // The clocks are on the shifted grid already; sending it as a
+            // new timeline would skip the ticks they are catching up on.
+            self.clock_timeline = Some(self.link.timeline(QUANTUM_BEATS));
+        }
+    }
+
+    /// Send a command to every MIDI clock output. A failing output only
+    /// affects itself.
+    fn each_clock(&self, command: impl Fn(&MidiClock) -> Result<(), MidiClockError>) {
+        for clock in &self.midi_clocks {
+            let _ = command(clock);
+        }
+    }
+
+    /// Follow a tempo change on free-running clock outputs; clocks that
+    /// follow the Link timeline pick it up from there.
+    fn set_free_clock_bpm(&self, bpm: f64) {
+        if self.free_clock {
+            self.each_clock(|clock| clock.set_bpm(bpm));
+        }
+    }
+
+    fn external_clock_locked(&self) -> bool {
+        self.clock_input
+            .as_ref()
+            .is_some_and(|input| input.is_locked())
+    }
+
+    /// Bridge the external MIDI clock into Link (and from there to our MIDI
+    /// clock output and LEDs).
+    fn sync_clock_input(&mut self) {
+        let Some(input) = self.clock_input.as_ref() else {
+            return;
+        };
+
+        for event in input.poll_events() {
+            match event {
+                ClockInputEvent::Tempo(bpm) => {
+                    if !self.clock_overridden {
+                        self.set_tempo(bpm);
+                    }
+                }
+                ClockInputEvent::Start => {
+                    println!("External MIDI clock START");
+                    self.clock_overridden = false;
+                    // Put the Link downbeat on the external START, and start
+                    // the clocks on that downbeat rather than the next one.
+                    let at = self.link.start_at_beat(0.0, QUANTUM_BEATS);
+                    self.start_playback(Some(at));
+                }
+                ClockInputEvent::Continue if !self.playing => {
+                    println!("External MIDI clock CONTINUE");
+                    self.resume();
+                }
+                ClockInputEvent::Stop if self.playing => {
+                    println!("External MIDI clock STOP");
+                    self.pause(None);
+                }
+                ClockInputEvent::Continue | ClockInputEvent::Stop => {}
+                ClockInputEvent::Lost => {
+                    println!("External MIDI clock lost");
+                    self.clock_overridden = false;
+                }
+            }
+        }
+    }
+
+    /// Follow tempo, transport and peer changes made by other Link peers.
+    fn sync_link(&mut self) {
+        for event in self.link.poll_events() {
+            match event {
+                LinkEvent::Tempo(bpm) => {
+                    self.current_bpm = bpm;
+                    self.set_free_clock_bpm(bpm);
+                    println!("Link tempo changed to {:.2} BPM", bpm);
+                }
+                LinkEvent::Playing(true) if !self.playing => {
+                    println!("Link transport started by a peer");
+                    if self.paused {
+                        self.resume_playback();
+                    } else {
+                        self.start_playback(None);
+                    }
+                }
+                LinkEvent::Playing(false) if self.playing => {
+                    println!("Link transport stopped by a peer");
+                    self.stop_playback(None);
+                }
+                LinkEvent::Playing(_) => {}
+                LinkEvent::Peers(peers) => println!("Link peers: {peers}"),
+            }
+        }
+    }
+
+    /// Hand the Link timeline to the MIDI clock whenever it has moved, so
+    /// clock ticks follow the same beat grid as Link peers and the LEDs.
+    fn sync_clock(&mut self) {
+        if self.free_clock || self.midi_clocks.is_empty() {
+            return;
+        }
+
+        let timeline = self.link.timeline(QUANTUM_BEATS);
+        let unchanged = self.clock_timeline.is_some_and(|last| {
+            last.tempo == timeline.tempo
+                && (last.beat_at(timeline.at) - timeline.beat).abs() < TIMELINE_TOLERANCE
+        });
+        if !unchanged {
+            self.each_clock(|clock| clock.set_timeline(timeline, QUANTUM_BEATS));
+            self.clock_timeline = Some(timeline);
+        }
+    }
+
+    fn update_led_feedback(&mut self, controller: &mut Controller) {
+        // MIDI feedback is the steady state; taps and the beat still show on top.
+        if let Some(feedback) = self.midi_feedback.as_ref() {
+            for (led, level) in feedback.poll() {
+                self.leds
+                    .set(Layer::Base, led, Effect::Solid(level), Timebase::Wall);
+            }
+        }
+
+        let mut beat = None;
+        if self.playing || self.armed.is_some() {
+            let now_micros = self.link.clock().micros();
+            self.link.with_session_state(|state| {
+                beat = Some(state.beat_at_time(now_micros, QUANTUM_BEATS));
+            });
+        }
+
+        let frame = self.leds.render(Instant::now(), beat);
+        controller.set_led_frame(&frame);
     }
 }
@@ -0,0 +1,485 @@
+//! YAML mapping from X1 controls to app actions (`board.yml`).
+
+use std::{fmt, fs, io, path::Path};
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::midi_clock::{Backend, ClockOutputConfig, PortSelector, MAX_LATENCY_MS};
+use crate::midi_surface::SurfaceConfig;
+use crate::tap_tempo::TapConfig;
+use crate::tempo::{MAX_BPM, MIN_BPM};
+use crate::x1_controller::{ButtonId, EncoderId, LedId, Modifiers, PotId};
+
+/// The mapping bundled with the app, also shipped as `board.yml`.
+const BUILTIN_MAPPING: &str = include_str!("../board.yml");
+
+/// Something the app can do in response to a control.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Register a tap for tap tempo.
+    Tap,
+    /// Start the transport if it is stopped.
+    Start,
+    /// Stop the transport if it is playing.
+    Stop,
+    /// Start or stop the transport.
+    Toggle,
+    /// Pause the transport, or continue from where it was paused.
+    Pause,
+    /// Jump the MIDI song position to this beat.
+    Locate(f64),
+    /// Move the beat grid this many milliseconds ahead (negative: back)
+    /// without changing the tempo; per detent on an encoder.
+    PhaseMs(f64),
+    /// Move the beat grid this fraction of a beat ahead (negative: back).
+    PhaseBeats(f64),
+    /// Make the current moment the first beat of a bar.
+    Resync,
+    /// Change the tempo by this many BPM (per detent when bound to an encoder).
+    Nudge(f64),
+    /// While the button is held, play this many percent faster (or slower,
+    /// if negative) than the set tempo, like a DJ pitch bend. Buttons only.
+    Bend(f64),
+    /// Jump to a fixed tempo.
+    SetBpm(f64),
+    /// Halve the current tempo.
+    HalfTempo,
+    /// Double the current tempo.
+    DoubleTempo,
+    /// Map a pot's position onto a tempo range.
+    BpmRange { min: f64, max: f64 },
+}
+
+/// Binds a button (optionally with Shift held or released) to an action.
+///
+/// `shift` left out matches either state. `led` is flashed when the action fires.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ButtonBinding {
+    pub button: ButtonId,
+    #[serde(default)]
+    pub shift: Option<bool>,
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub action: Action,
+    #[serde(default)]
+    pub led: Option<LedId>,
+}
+
+/// Binds an encoder to an action; `Nudge` is scaled by the detent count.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncoderBinding {
+    pub encoder: EncoderId,
+    #[serde(default)]
+    pub shift: Option<bool>,
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub action: Action,
+    #[serde(default)]
+    pub led: Option<LedId>,
+}
+
+/// Binds a pot to an action; `BpmRange` follows the normalized pot position.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PotBinding {
+    pub pot: PotId,
+    #[serde(default)]
+    pub shift: Option<bool>,
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub action: Action,
+    #[serde(default)]
+    pub led: Option<LedId>,
+}
+
+/// How start and stop line up with the beat grid.
+///
+/// When `quantized`, the transport starts and stops on the next bar line of
+/// the Link session, after `count_in` extra bars when starting. Otherwise it
+/// changes right away.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransportConfig {
+    #[serde(default = "default_quantized")]
+    pub quantized: bool,
+    #[serde(default)]
+    pub count_in: u32,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            quantized: default_quantized(),
+            count_in: 0,
+        }
+    }
+}
+
+fn default_quantized() -> bool {
+    true
+}
+
+/// Control-to-action mapping loaded from YAML.
+///
+/// Bindings are matched in file order; the first one whose control and
+/// modifiers match wins. `beat_led` blinks along with the Link beat while the
+/// transport is playing. `clock_outputs` lists the MIDI ports that receive
+/// clock. `midi_surface` turns on the virtual MIDI controller port,
+/// `tap_tempo` tunes tap tempo detection, and `transport` sets how start
+/// and stop are quantized.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mapping {
+    #[serde(default)]
+    pub beat_led: Option<LedId>,
+    #[serde(default)]
+    pub buttons: Vec<ButtonBinding>,
+    #[serde(default)]
+    pub encoders: Vec<EncoderBinding>,
+    #[serde(default)]
+    pub pots: Vec<PotBinding>,
+    #[serde(default)]
+    pub clock_outputs: Vec<ClockOutputConfig>,
+    #[serde(default)]
+    pub midi_surface: Option<SurfaceConfig>,
+    #[serde(default)]
+    pub tap_tempo: TapConfig,
+    #[serde(default)]
+    pub transport: TransportConfig,
+}
+
+impl Mapping {
+    /// The default layout (Shift+Deck A Sync taps, Deck A Play toggles).
+    pub fn builtin() -> Self {
+        Self::from_yaml(BUILTIN_MAPPING).expect("bundled board.yml must be valid")
+    }
+
+    /// Load a mapping file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, MappingError> {
+        let text = fs::read_to_string(path).map_err(MappingError::Io)?;
+        Self::from_yaml(&text)
+    }
+
+    /// Parse and validate a mapping from YAML text.
+    pub fn from_yaml(text: &str) -> Result<Self, MappingError> {
+        let mapping: Mapping = serde_yaml::from_str(text).map_err(MappingError::Parse)?;
+        mapping.validate()?;
+        Ok(mapping)
+    }
+
+    /// First button binding matching `id` and `modifiers`.
+    pub fn button(&self, id: ButtonId, modifiers: Modifiers) -> Option<&ButtonBinding> {
+        self.buttons
+            .iter()
+            .find(|b| b.button == id && shift_matches(b.shift, modifiers))
+    }
+
+    /// First encoder binding matching `id` and `modifiers`.
+    pub fn encoder(&self, id: EncoderId, modifiers: Modifiers) -> Option<&EncoderBinding> {
+        self.encoders
+            .iter()
+            .find(|b| b.encoder == id && shift_matches(b.shift, modifiers))
+    }
+
+    /// First pot binding matching `id` and `modifiers`.
+    pub fn pot(&self, id: PotId, modifiers: Modifiers) -> Option<&PotBinding> {
+        self.pots
+            .iter()
+            .find(|b| b.pot == id && shift_matches(b.shift, modifiers))
+    }
+
+    fn validate(&self) -> Result<(), MappingError> {
+        let bend_without_button = self
+            .encoders
+            .iter()
+            .map(|b| b.action)
+            .chain(self.pots.iter().map(|b| b.action))
+            .find(|action| matches!(action, Action::Bend(_)));
+        if let Some(action) = bend_without_button {
+            return Err(MappingError::Invalid(format!(
+                "{action:?} needs a button to hold"
+            )));
+        }
+        let actions = self
+            .buttons
+            .iter()
+            .map(|b| b.action)
+            .chain(self.encoders.iter().map(|b| b.action))
+            .chain(self.pots.iter().map(|b| b.action));
+        for output in &self.clock_outputs {
+            if let PortSelector::Regex(pattern) = &output.port {
+                Regex::new(pattern).map_err(|err| {
+                    MappingError::Invalid(format!("clock output regex {pattern}: {err}"))
+                })?;
+            }
+            if matches!(output.port, PortSelector::Virtual(_)) && output.backend != Backend::AlsaSeq
+            {
+                return Err(MappingError::Invalid(format!(
+                    "clock output {}: virtual ports need `backend: alsa_seq`",
+                    output.port
+                )));
+            }
+            if !output.latency_ms.is_finite() || output.latency_ms.abs() > MAX_LATENCY_MS {
+                return Err(MappingError::Invalid(format!(
+                    "clock output latency_ms {} must be within -{MAX_LATENCY_MS}..{MAX_LATENCY_MS}",
+                    output.latency_ms
+                )));
+            }
+            if output.ratio <= 0.0 {
+                return Err(MappingError::Invalid(format!(
+                    "clock output ratio {} must be positive",
+                    output.ratio
+                )));
+            }
+        }
+        let tap = &self.tap_tempo;
+        if tap.taps_needed < 2 || tap.reset_gap < 0.0 {
+            return Err(MappingError::Invalid(format!(
+                "tap_tempo needs at least 2 taps and a non-negative reset_gap, got {} and {}",
+                tap.taps_needed, tap.reset_gap
+            )));
+        }
+        if let Some(range) = tap.preferred_range.filter(|range| !range.is_valid()) {
+            return Err(MappingError::Invalid(format!(
+                "tap_tempo preferred_range {}..{} must be increasing and within {MIN_BPM}..{MAX_BPM}",
+                range.min, range.max
+            )));
+        }
+        if let Some(window) = tap.rolling_window.filter(|&w| w < tap.taps_needed) {
+            return Err(MappingError::Invalid(format!(
+                "tap_tempo rolling_window {window} must cover taps_needed ({})",
+                tap.taps_needed
+            )));
+        }
+        if let Some(tolerance) = tap.outlier_tolerance.filter(|&t| t <= 0.0) {
+            return Err(MappingError::Invalid(format!(
+                "tap_tempo outlier_tolerance {tolerance} must be positive"
+            )));
+        }
+        if let Some(surface) = &self.midi_surface {
+            surface
+                .validate()
+                .map_err(|err| MappingError::Invalid(format!("midi_surface: {err}")))?;
+        }
+        for action in actions {
+            match action {
+                Action::SetBpm(bpm) if !(MIN_BPM..=MAX_BPM).contains(&bpm) => {
+                    return Err(MappingError::Invalid(format!(
+                        "set_bpm {bpm} must be within {MIN_BPM}..{MAX_BPM}"
+                    )))
+                }
+                Action::BpmRange { min, max } if min < MIN_BPM || max <= min || max > MAX_BPM => {
+                    return Err(MappingError::Invalid(format!(
+                        "bpm_range {min}..{max} must be increasing and within {MIN_BPM}..{MAX_BPM}"
+                    )))
+                }
+                Action::Bend(percent) if !(-50.0..=50.0).contains(&percent) => {
+                    return Err(MappingError::Invalid(format!(
+                        "bend {percent}% must be within -50..50"
+                    )))
+                }
+                Action::Locate(beat) if beat < 0.0 => {
+                    return Err(MappingError::Invalid(format!(
+                        "locate {beat} must not be negative"
+                    )))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+fn shift_matches(required: Option<bool>, modifiers: Modifiers) -> bool {
+    required.is_none() || required == Some(modifiers.shift)
+}
+
+/// Errors from loading a [`Mapping`].
+#[derive(Debug)]
+pub enum MappingError {
+    Io(io::Error),
+    Parse(serde_yaml::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for MappingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MappingError::Io(err) => write!(f, "failed to read mapping: {err}"),
+            MappingError::Parse(err) => write!(f, "failed to parse mapping: {err}"),
+            MappingError::Invalid(err) => write!(f, "invalid mapping: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for MappingError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHIFT: Modifiers = Modifiers { shift: true };
+    const NO_SHIFT: Modifiers = Modifiers { shift: false };
+
+    #[test]
+    fn builtin_mapping_matches_default_layout() {
+        let mapping = Mapping::builtin();
+        assert_eq!(
+            mapping.button(ButtonId::Deck1Sync, SHIFT).map(|b| b.action),
+            Some(Action::Tap)
+        );
+        assert!(mapping.button(ButtonId::Deck1Sync, NO_SHIFT).is_none());
+        assert_eq!(
+            mapping.button(ButtonId::Deck1Play, SHIFT).map(|b| b.action),
+            Some(Action::Toggle)
+        );
+        assert_eq!(mapping.beat_led, Some(LedId::Deck1Sync));
+        assert_eq!(
+            mapping
+                .button(ButtonId::Deck1BeatLeft, NO_SHIFT)
+                .map(|b| b.action),
+            Some(Action::Bend(-4.0))
+        );
+        assert_eq!(
+            mapping
+                .encoder(EncoderId::Deck1Browse, SHIFT)
+                .map(|b| b.action),
+            Some(Action::Nudge(1.0))
+        );
+    }
+
+    #[test]
+    fn parses_parameterised_actions() {
+        let mapping = Mapping::from_yaml(
+            "
+buttons:
+  - button: Hotcue
+    action: { locate: 0 }
+encoders:
+  - encoder: Deck1Browse
+    action: { nudge: 0.5 }
+pots:
+  - pot: Deck1DryWet
+    shift: true
+    action:
+      bpm_range: { min: 80, max: 160 }
+",
+        )
+        .unwrap();
+        assert_eq!(
+            mapping.button(ButtonId::Hotcue, NO_SHIFT).unwrap().action,
+            Action::Locate(0.0)
+        );
+        assert_eq!(
+            mapping
+                .encoder(EncoderId::Deck1Browse, SHIFT)
+                .unwrap()
+                .action,
+            Action::Nudge(0.5)
+        );
+        assert!(mapping.pot(PotId::Deck1DryWet, NO_SHIFT).is_none());
+        assert_eq!(
+            mapping.pot(PotId::Deck1DryWet, SHIFT).unwrap().action,
+            Action::BpmRange {
+                min: 80.0,
+                max: 160.0
+            }
+        );
+    }
+
+    #[test]
+    fn parses_phase_actions() {
+        let mapping = Mapping::from_yaml(
+            "
+buttons:
+  - { button: Deck1Out, action: resync }
+  - { button: Deck1In, action: { phase_beats: -0.25 } }
+encoders:
+  - { encoder: Deck1Loop, action: { phase_ms: 5 } }
+",
+        )
+        .unwrap();
+        let actions: Vec<_> = mapping.buttons.iter().map(|b| b.action).collect();
+        assert_eq!(actions, [Action::Resync, Action::PhaseBeats(-0.25)]);
+        assert_eq!(mapping.encoders[0].action, Action::PhaseMs(5.0));
+    }
+
+    #[test]
+    fn parses_transport_settings() {
+        assert_eq!(Mapping::builtin().transport, TransportConfig::default());
+        assert!(TransportConfig::default().quantized);
+
+        let mapping = Mapping::from_yaml("transport: { count_in: 1 }").unwrap();
+        assert_eq!(
+            mapping.transport,
+            TransportConfig {
+                quantized: true,
+                count_in: 1
+            }
+        );
+        let mapping = Mapping::from_yaml("transport: { quantized: false }").unwrap();
+        assert!(!mapping.transport.quantized);
+    }
+
+    #[test]
+    fn parses_clock_outputs() {
+        let mapping = Mapping::from_yaml(
+            "
+clock_outputs:
+  - port: { name: Volca }
+    latency_ms: 4.5
+  - port: { regex: '^TR-8' }
+    ratio: 0.5
+  - port: { index: 2 }
+    enabled: false
+  - port: { virtual: X1 Clock }
+    backend: alsa_seq
+",
+        )
+        .unwrap();
+        let ports: Vec<_> = mapping.clock_outputs.iter().map(|o| &o.port).collect();
+        assert_eq!(
+            ports,
+            [
+                &PortSelector::Name("Volca".into()),
+                &PortSelector::Regex("^TR-8".into()),
+                &PortSelector::Index(2),
+                &PortSelector::Virtual("X1 Clock".into())
+            ]
+        );
+        assert_eq!(mapping.clock_outputs[0].backend, Backend::Midir);
+        assert_eq!(mapping.clock_outputs[3].backend, Backend::AlsaSeq);
+        assert_eq!(mapping.clock_outputs[0].latency_ms, 4.5);
+        assert_eq!(mapping.clock_outputs[0].ratio, 1.0);
+        assert_eq!(mapping.clock_outputs[1].ratio, 0.5);
+        assert!(!mapping.clock_outputs[2].enabled);
+
+        let err = Mapping::from_yaml("clock_outputs:\n  - port: { regex: '(' }");
+        assert!(matches!(err, Err(MappingError::Invalid(_))));
+        let err = Mapping::from_yaml("clock_outputs:\n  - port: { virtual: Clock }");
+        assert!(matches!(err, Err(MappingError::Invalid(_))));
+        for latency in [".nan", ".inf", "-1e300", "1500"] {
+            let yaml =
+                format!("clock_outputs:\n  - {{ port: {{ index: 0 }}, latency_ms: {latency} }}");
+            let err = Mapping::from_yaml(&yaml);
+            assert!(matches!(err, Err(MappingError::Invalid(_))), "{latency}");
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        let err = Mapping::from_yaml("buttons:\n  - { button: Hotcue, action: tap, led: Nope }");
+        assert!(matches!(err, Err(MappingError::Parse(_))));
+        let err = Mapping::from_yaml("buttons:\n  - { button: Hotcue, action: { set_bpm: 0 } }");
+        assert!(matches!(err, Err(MappingError::Invalid(_))));
+        let err = Mapping::from_yaml("encoders:\n  - { encoder: Deck1Loop, action: { bend: 2 } }");
+        assert!(matches!(err, Err(MappingError::Invalid(_))));
+        let err = Mapping::from_yaml("tap_tempo: { preferred_range: { min: 90, max: 80 } }");
+        assert!(matches!(err, Err(MappingError::Invalid(_))));
+        let err = Mapping::from_yaml("tap_tempo: { taps_needed: 1 }");
+        assert!(matches!(err, Err(MappingError::Invalid(_))));
+        let err = Mapping::from_yaml("midi_surface: { channel: 17 }");
+        assert!(matches!(err, Err(MappingError::Invalid(_))));
+    }
+}
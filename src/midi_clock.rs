@@ -1,5 +1,13 @@
-use midir::{MidiOutput, MidiOutputConnection, SendError};
+//! 24 PPQN MIDI clock generator running on its own thread.
+
+use crate::tempo::clamp_bpm;
+use crate::timeline::Timeline;
+use midir::{MidiIO, MidiOutput, MidiOutputConnection, SendError};
+use regex::Regex;
+use serde::Deserialize;
 use std::{
+    collections::VecDeque,
+    error::Error,
     fmt,
     sync::mpsc::{self, RecvTimeoutError, Sender},
     thread,
@@ -7,10 +15,122 @@ use std::{
 };
 
 const PPQN: f64 = 24.0;
-const MIN_BPM: f64 = 30.0;
-const MAX_BPM: f64 = 300.0;
 const THREAD_NAME: &str = "x1-tap-clock-midi";
+/// Largest `latency_ms`, early or late, that a clock output accepts.
+pub const MAX_LATENCY_MS: f64 = 1000.0;
+
+/// How a MIDI port is picked from the ports of the system.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PortSelector {
+    /// First port whose name contains this (case-insensitive); an empty
+    /// name selects the first port.
+    Name(String),
+    /// First port whose name matches this regular expression.
+    Regex(String),
+    /// Port at this position in the enumeration order.
+    Index(usize),
+    /// A new port with this name that other applications connect to
+    /// (`alsa_seq` backend only).
+    Virtual(String),
+}
+
+impl PortSelector {
+    /// Find the selected port among the ports of `midi`.
+    pub fn find<M: MidiIO>(&self, midi: &M) -> Option<M::Port> {
+        let mut ports = midi.ports().into_iter();
+        let name_matches = |port: &M::Port, matches: &dyn Fn(&str) -> bool| {
+            midi.port_name(port)
+                .map(|name| matches(&name))
+                .unwrap_or(false)
+        };
+
+        match self {
+            PortSelector::Name(hint) if hint.trim().is_empty() => ports.next(),
+            PortSelector::Name(hint) => {
+                let hint = hint.to_lowercase();
+                ports.find(|port| name_matches(port, &|name| name.to_lowercase().contains(&hint)))
+            }
+            PortSelector::Regex(pattern) => {
+                let regex = Regex::new(pattern).ok()?;
+                ports.find(|port| name_matches(port, &|name| regex.is_match(name)))
+            }
+            PortSelector::Index(index) => ports.nth(*index),
+            PortSelector::Virtual(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for PortSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PortSelector::Name(name) => write!(f, "{name}"),
+            PortSelector::Regex(pattern) => write!(f, "/{pattern}/"),
+            PortSelector::Index(index) => write!(f, "#{index}"),
+            PortSelector::Virtual(name) => write!(f, "virtual port {name}"),
+        }
+    }
+}
 
+/// How clock messages reach an output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    /// Send each message through `midir` when it is due.
+    #[default]
+    Midir,
+    /// Queue messages ahead of time with exact timestamps on an ALSA
+    /// sequencer queue (Linux, `alsa-seq` feature).
+    AlsaSeq,
+}
+
+/// Settings of one MIDI clock output, as listed under `clock_outputs` in
+/// the mapping file.
+///
+/// `latency_ms` sends everything that much earlier to make up for a slow
+/// device (negative values delay it), up to [`MAX_LATENCY_MS`] either way.
+/// `ratio` multiplies the clock rate:
+/// `2` runs the follower at double time, `0.5` at half time.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClockOutputConfig {
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub port: PortSelector,
+    #[serde(default)]
+    pub latency_ms: f64,
+    #[serde(default = "default_ratio")]
+    pub ratio: f64,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub backend: Backend,
+}
+
+impl ClockOutputConfig {
+    /// Output on the port found by `selector` with default settings.
+    pub fn new(port: PortSelector) -> Self {
+        Self {
+            port,
+            latency_ms: 0.0,
+            ratio: default_ratio(),
+            enabled: default_enabled(),
+            backend: Backend::default(),
+        }
+    }
+}
+
+fn default_ratio() -> f64 {
+    1.0
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// MIDI clock output on a single port.
+///
+/// Clock ticks are produced by a background thread; the methods here only
+/// send commands to it and never block on MIDI I/O.
 #[derive(Debug)]
 pub struct MidiClock {
     tx: Sender<Command>,
@@ -19,30 +139,38 @@ pub struct MidiClock {
 }
 
 impl MidiClock {
+    /// Open the first output port whose name contains `port_hint`
+    /// (case-insensitive), or the first port if the hint is empty.
     pub fn new(port_hint: &str, initial_bpm: f64) -> Result<Self, MidiClockError> {
-        let midi_out = MidiOutput::new("x1-tap-clock")
-            .map_err(|err| MidiClockError::MidiInit(err.to_string()))?;
-        let ports = midi_out.ports();
+        Self::open(
+            &ClockOutputConfig::new(PortSelector::Name(port_hint.into())),
+            initial_bpm,
+        )
+    }
 
-        if ports.is_empty() {
-            return Err(MidiClockError::PortNotFound(port_hint.to_string()));
+    /// Open the output described by `config`.
+    ///
+    /// Every output runs its own clock thread, so a slow or failing port
+    /// does not hold up the others.
+    pub fn open(config: &ClockOutputConfig, initial_bpm: f64) -> Result<Self, MidiClockError> {
+        match config.backend {
+            Backend::Midir => Self::open_midir(config, initial_bpm),
+            Backend::AlsaSeq => Self::open_alsa_seq(config, initial_bpm),
         }
+    }
 
-        let target_port = if port_hint.trim().is_empty() {
-            ports[0].clone()
-        } else {
-            let hint = port_hint.to_lowercase();
-            ports
-                .iter()
-                .find(|port| {
-                    midi_out
-                        .port_name(port)
-                        .map(|name| name.to_lowercase().contains(&hint))
-                        .unwrap_or(false)
-                })
-                .cloned()
-                .ok_or_else(|| MidiClockError::PortNotFound(port_hint.to_string()))?
-        };
+    fn open_midir(config: &ClockOutputConfig, initial_bpm: f64) -> Result<Self, MidiClockError> {
+        if let PortSelector::Virtual(_) = config.port {
+            return Err(MidiClockError::Unsupported(
+                "virtual ports need the alsa_seq backend".into(),
+            ));
+        }
+        let midi_out = MidiOutput::new("x1-tap-clock")
+            .map_err(|err| MidiClockError::MidiInit(err.to_string()))?;
+        let target_port = config
+            .port
+            .find(&midi_out)
+            .ok_or_else(|| MidiClockError::PortNotFound(config.port.to_string()))?;
 
         let port_name = midi_out
             .port_name(&target_port)
@@ -52,14 +180,42 @@ impl MidiClock {
             .connect(&target_port, "x1-tap-clock-out")
             .map_err(|err| MidiClockError::Connection(err.to_string()))?;
 
+        Self::spawn(connection, config, initial_bpm, port_name)
+    }
+
+    #[cfg(all(target_os = "linux", feature = "alsa-seq"))]
+    fn open_alsa_seq(config: &ClockOutputConfig, initial_bpm: f64) -> Result<Self, MidiClockError> {
+        let sink = crate::alsa_seq::AlsaSeqSink::open(&config.port)?;
+        let port_name = sink.port_name().to_string();
+        Self::spawn(sink, config, initial_bpm, port_name)
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "alsa-seq")))]
+    fn open_alsa_seq(
+        _config: &ClockOutputConfig,
+        _initial_bpm: f64,
+    ) -> Result<Self, MidiClockError> {
+        Err(MidiClockError::Unsupported(
+            "the alsa_seq backend needs Linux and the alsa-seq feature".into(),
+        ))
+    }
+
+    /// Run a clock thread that sends to `sink`.
+    fn spawn<S: ClockSink + Send + 'static>(
+        sink: S,
+        config: &ClockOutputConfig,
+        initial_bpm: f64,
+        port_name: String,
+    ) -> Result<Self, MidiClockError> {
         let (tx, rx) = mpsc::channel::<Command>();
 
-        let initial_bpm = sanitize_bpm(initial_bpm);
+        let initial_bpm = clamp_bpm(initial_bpm);
         let port_label = port_name.clone();
+        let config = config.clone();
 
         let thread = thread::Builder::new()
             .name(THREAD_NAME.into())
-            .spawn(move || run_clock(connection, rx, initial_bpm, port_label))
+            .spawn(move || run_clock(sink, rx, initial_bpm, config, port_label))
             .map_err(|err| MidiClockError::Thread(err.to_string()))?;
 
         Ok(Self {
@@ -69,20 +225,87 @@ impl MidiClock {
         })
     }
 
+    /// Name of the connected output port.
     pub fn port_name(&self) -> &str {
         &self.port_name
     }
 
+    /// Send START and begin emitting clock ticks.
     pub fn start(&self) -> Result<(), MidiClockError> {
         self.send_command(Command::Start)
     }
 
+    /// Send START at `at` instead of on the next bar, e.g. at the end of a
+    /// count-in. When following a timeline, the song starts at the beat the
+    /// timeline is on at `at`.
+    pub fn start_at(&self, at: Instant) -> Result<(), MidiClockError> {
+        self.send_command(Command::StartAt(at))
+    }
+
+    /// Send STOP and stop emitting clock ticks.
     pub fn stop(&self) -> Result<(), MidiClockError> {
         self.send_command(Command::Stop)
     }
 
+    /// Keep emitting clock ticks until `at`, then send STOP. The stop moves
+    /// with the beat grid if the tempo or phase changes in the meantime;
+    /// starting or continuing again cancels it.
+    pub fn stop_at(&self, at: Instant) -> Result<(), MidiClockError> {
+        self.send_command(Command::StopAt(at))
+    }
+
+    /// Send STOP but keep the song position, so [`resume`](Self::resume)
+    /// carries on from where the clock was paused.
+    pub fn pause(&self) -> Result<(), MidiClockError> {
+        self.send_command(Command::Pause)
+    }
+
+    /// Drop a STOP scheduled with [`stop_at`](Self::stop_at) and keep
+    /// running as if it had never been asked for.
+    pub fn cancel_stop(&self) -> Result<(), MidiClockError> {
+        self.send_command(Command::CancelStop)
+    }
+
+    /// Send a Song Position Pointer for the paused position followed by
+    /// CONTINUE, and resume emitting clock ticks.
+    ///
+    /// When following a timeline, CONTINUE is delayed until the song
+    /// position is in phase with the timeline's bars again.
+    pub fn resume(&self) -> Result<(), MidiClockError> {
+        self.send_command(Command::Resume)
+    }
+
+    /// Jump to `beat` (rounded down to a sixteenth note) with a Song
+    /// Position Pointer. A running clock is stopped and continues from the
+    /// new position.
+    pub fn locate(&self, beat: f64) -> Result<(), MidiClockError> {
+        self.send_command(Command::Locate(beat))
+    }
+
+    /// Change the tick rate; the tempo is clamped to the supported range.
     pub fn set_bpm(&self, bpm: f64) -> Result<(), MidiClockError> {
-        self.send_command(Command::SetBpm(sanitize_bpm(bpm)))
+        self.send_command(Command::SetBpm(clamp_bpm(bpm)))
+    }
+
+    /// Follow `timeline` instead of the internal tempo.
+    ///
+    /// Clock ticks are scheduled on the timeline's beat grid, so they stay
+    /// phase-aligned with it across tempo changes; publish a fresh snapshot
+    /// whenever the source timeline changes. From then on START lands on the
+    /// next multiple of `quantum` beats.
+    pub fn set_timeline(&self, timeline: Timeline, quantum: f64) -> Result<(), MidiClockError> {
+        self.send_command(Command::SetTimeline { timeline, quantum })
+    }
+
+    /// Move the beat grid `beats` ahead (or back, if negative) without
+    /// changing the tempo, e.g. after the Link phase was shifted on purpose.
+    ///
+    /// Unlike a jump in [`set_timeline`](Self::set_timeline), followers are
+    /// kept in phase: ticks the grid moved past are sent right away instead
+    /// of being skipped, and moving back holds the clock until the grid has
+    /// caught up.
+    pub fn shift_phase(&self, beats: f64) -> Result<(), MidiClockError> {
+        self.send_command(Command::ShiftPhase(beats))
     }
 
     fn send_command(&self, command: Command) -> Result<(), MidiClockError> {
@@ -102,23 +325,28 @@ impl Drop for MidiClock {
     }
 }
 
+/// Errors from opening or driving a [`MidiClock`] or [`MidiClockInput`].
+///
+/// [`MidiClockInput`]: crate::midi_clock_input::MidiClockInput
 #[derive(Debug)]
 pub enum MidiClockError {
     MidiInit(String),
     PortNotFound(String),
     Connection(String),
     Thread(String),
+    Unsupported(String),
 }
 
 impl fmt::Display for MidiClockError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            MidiClockError::MidiInit(err) => write!(f, "failed to initialise MIDI output: {err}"),
+            MidiClockError::MidiInit(err) => write!(f, "failed to initialise MIDI: {err}"),
             MidiClockError::PortNotFound(port) => {
-                write!(f, "no MIDI output port matching \"{port}\" was found")
+                write!(f, "no MIDI port matching \"{port}\" was found")
             }
             MidiClockError::Connection(err) => write!(f, "failed to open MIDI connection: {err}"),
             MidiClockError::Thread(err) => write!(f, "midi clock thread error: {err}"),
+            MidiClockError::Unsupported(err) => write!(f, "unsupported clock output: {err}"),
         }
     }
 }
@@ -128,128 +356,927 @@ impl std::error::Error for MidiClockError {}
 #[derive(Debug)]
 enum Command {
     Start,
+    StartAt(Instant),
     Stop,
+    StopAt(Instant),
+    CancelStop,
+    Pause,
+    Resume,
+    Locate(f64),
     SetBpm(f64),
+    SetTimeline { timeline: Timeline, quantum: f64 },
+    ShiftPhase(f64),
     Shutdown,
 }
 
-fn run_clock(
-    mut connection: MidiOutputConnection,
+/// MIDI messages sent by the clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Message {
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    /// Song Position Pointer, in sixteenth notes since the song start.
+    SongPosition(u16),
+}
+
+impl Message {
+    fn name(self) -> &'static str {
+        match self {
+            Message::Clock => "CLOCK",
+            Message::Start => "START",
+            Message::Continue => "CONTINUE",
+            Message::Stop => "STOP",
+            Message::SongPosition(_) => "SONG POSITION",
+        }
+    }
+}
+
+/// Sixteenth notes per beat, the unit of Song Position Pointer.
+const SIXTEENTHS: f64 = 4.0;
+const MAX_SONG_POSITION: u16 = 0x3FFF;
+/// Time between a free-running START or any CONTINUE being requested and
+/// the beat it lands on, on top of the output latency. Gives followers time
+/// to handle a Song Position Pointer.
+const SCHEDULE_LEAD: Duration = Duration::from_millis(50);
+/// Messages queued for less than this from now are left to go out when a
+/// plan changes, since removing them could race with their delivery.
+const REVOKE_GUARD: Duration = Duration::from_millis(1);
+
+/// Where a clock thread sends its messages.
+pub(crate) trait ClockSink {
+    /// How far ahead of their due time messages are handed to [`send`](Self::send).
+    fn lookahead(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    /// Send `message` so that it goes out at `at`, or right away if `at`
+    /// has passed.
+    fn send(&mut self, message: Message, at: Instant) -> Result<(), Box<dyn Error>>;
+
+    /// Drop messages that were sent ahead of time for after `after`.
+    fn revoke(&mut self, _after: Instant) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+impl ClockSink for MidiOutputConnection {
+    fn send(&mut self, message: Message, _at: Instant) -> Result<(), Box<dyn Error>> {
+        send_message(self, message)?;
+        Ok(())
+    }
+}
+
+fn run_clock<S: ClockSink>(
+    mut sink: S,
     rx: mpsc::Receiver<Command>,
     initial_bpm: f64,
+    config: ClockOutputConfig,
     port_name: String,
 ) {
-    let mut bpm = initial_bpm;
-    let mut tick_duration = duration_from_bpm(bpm);
-    let mut running = false;
-    let mut next_tick = Instant::now();
+    let mut scheduler = Scheduler::new(initial_bpm, Instant::now(), &config);
+    // Messages handed to the sink ahead of time, with the scheduler state
+    // from before each one, so a change of plan can take them back.
+    let mut pending: VecDeque<(Instant, Checkpoint)> = VecDeque::new();
 
     loop {
-        if running {
-            let now = Instant::now();
-            if now >= next_tick {
-                if let Err(err) = send_byte(&mut connection, 0xF8) {
-                    eprintln!(
-                        "midi clock ({}): failed to send CLOCK message: {}",
-                        port_name, err
-                    );
-                    running = false;
-                } else {
-                    next_tick = next_tick
-                        .checked_add(tick_duration)
-                        .unwrap_or_else(Instant::now);
-                }
-                continue;
-            }
+        let now = Instant::now();
+        while pending.front().is_some_and(|&(at, _)| at <= now) {
+            pending.pop_front();
+        }
 
-            let timeout = next_tick - now;
-            match rx.recv_timeout(timeout) {
-                Ok(Command::Start) => {
-                    if let Err(err) = send_byte(&mut connection, 0xFA) {
-                        eprintln!(
-                            "midi clock ({}): failed to send START message: {}",
-                            port_name, err
-                        );
-                        running = false;
-                    } else {
-                        running = true;
-                        next_tick = Instant::now();
-                    }
-                }
-                Ok(Command::Stop) => {
-                    if let Err(err) = send_byte(&mut connection, 0xFC) {
-                        eprintln!(
-                            "midi clock ({}): failed to send STOP message: {}",
-                            port_name, err
-                        );
-                    }
-                    running = false;
-                }
-                Ok(Command::SetBpm(new_bpm)) => {
-                    bpm = new_bpm;
-                    tick_duration = duration_from_bpm(bpm);
-                    next_tick = Instant::now()
-                        .checked_add(tick_duration)
-                        .unwrap_or_else(Instant::now);
-                }
-                Ok(Command::Shutdown) => {
-                    if running {
-                        if let Err(err) = send_byte(&mut connection, 0xFC) {
+        let horizon = now + sink.lookahead();
+        let command = match scheduler.deadline() {
+            Some(deadline) if deadline <= horizon => {
+                let before = scheduler.checkpoint();
+                if let Some(message) = scheduler.fire() {
+                    match sink.send(message, deadline) {
+                        Ok(()) if deadline > now => pending.push_back((deadline, before)),
+                        Ok(()) => {}
+                        Err(err) => {
                             eprintln!(
-                                "midi clock ({}): failed to send STOP message: {}",
-                                port_name, err
+                                "midi clock ({}): failed to send {} message: {}",
+                                port_name,
+                                message.name(),
+                                err
                             );
+                            scheduler.halt();
                         }
                     }
-                    break;
                 }
+                continue;
+            }
+            Some(deadline) => match rx.recv_timeout(deadline - horizon) {
+                Ok(command) => command,
                 Err(RecvTimeoutError::Timeout) => continue,
                 Err(RecvTimeoutError::Disconnected) => break,
+            },
+            None => match rx.recv() {
+                Ok(command) => command,
+                Err(_) => break,
+            },
+        };
+
+        // Every command changes the plan; re-plan from the first message
+        // that can still be taken back.
+        let cutoff = Instant::now() + REVOKE_GUARD;
+        while pending.front().is_some_and(|&(at, _)| at <= cutoff) {
+            pending.pop_front();
+        }
+        if let Some(&(_, checkpoint)) = pending.front() {
+            if let Err(err) = sink.revoke(cutoff) {
+                eprintln!(
+                    "midi clock ({}): failed to revoke queued messages: {}",
+                    port_name, err
+                );
             }
-        } else {
-            match rx.recv() {
-                Ok(Command::Start) => {
-                    if let Err(err) = send_byte(&mut connection, 0xFA) {
-                        eprintln!(
-                            "midi clock ({}): failed to send START message: {}",
-                            port_name, err
-                        );
-                        running = false;
-                    } else {
-                        running = true;
-                        next_tick = Instant::now();
-                    }
-                }
-                Ok(Command::Stop) => {
-                    if let Err(err) = send_byte(&mut connection, 0xFC) {
-                        eprintln!(
-                            "midi clock ({}): failed to send STOP message: {}",
-                            port_name, err
-                        );
-                    }
-                }
-                Ok(Command::SetBpm(new_bpm)) => {
-                    bpm = new_bpm;
-                    tick_duration = duration_from_bpm(bpm);
+            scheduler.restore(checkpoint);
+            pending.clear();
+        }
+
+        let now = Instant::now();
+        let messages = match command {
+            Command::Start => {
+                scheduler.start(now);
+                Vec::new()
+            }
+            Command::StartAt(at) => {
+                scheduler.start_at(at, now);
+                Vec::new()
+            }
+            Command::Stop => scheduler.stop(),
+            Command::StopAt(at) => {
+                scheduler.stop_at(at);
+                Vec::new()
+            }
+            Command::CancelStop => {
+                scheduler.stop_beat = None;
+                Vec::new()
+            }
+            Command::Pause => scheduler.pause(),
+            Command::Resume => scheduler.resume(now),
+            Command::Locate(beat) => scheduler.locate(beat, now),
+            Command::SetBpm(bpm) => {
+                scheduler.set_bpm(bpm, now);
+                Vec::new()
+            }
+            Command::SetTimeline { timeline, quantum } => {
+                scheduler.set_timeline(timeline, quantum, now);
+                Vec::new()
+            }
+            Command::ShiftPhase(beats) => {
+                scheduler.shift_phase(beats);
+                Vec::new()
+            }
+            Command::Shutdown => {
+                if scheduler.is_running() {
+                    scheduler.stop()
+                } else {
+                    Vec::new()
                 }
-                Ok(Command::Shutdown) => break,
-                Err(_) => break,
             }
+        };
+        for message in messages {
+            if let Err(err) = sink.send(message, now) {
+                eprintln!(
+                    "midi clock ({}): failed to send {} message: {}",
+                    port_name,
+                    message.name(),
+                    err
+                );
+            }
+        }
+        if matches!(command, Command::Shutdown) {
+            break;
         }
     }
+}
 
-    let _ = connection.close();
+/// Transport state of the clock thread.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transport {
+    Stopped,
+    /// START is due when the timeline reaches `beat`.
+    Starting {
+        beat: f64,
+    },
+    /// CONTINUE from the stored song position is due when the timeline
+    /// reaches `beat`.
+    Continuing {
+        beat: f64,
+    },
+    /// Clock tick number `tick` (in 1/24 beats on the timeline) is due next.
+    Running {
+        tick: i64,
+    },
 }
 
-fn send_byte(connection: &mut MidiOutputConnection, byte: u8) -> Result<(), SendError> {
-    connection.send(&[byte])
+/// Tick scheduling of the clock thread, kept free of MIDI I/O.
+///
+/// Every message is due at a beat position on `timeline`, so a tempo change
+/// only moves the remaining ticks instead of restarting the phase. In follow
+/// mode the timeline comes from outside (the Link session) and START waits
+/// for the next multiple of `quantum` beats.
+///
+/// The song position is the number of beats since the last START. While
+/// running it is derived from the timeline (`origin` is the timeline beat of
+/// song position zero); while stopped it is kept in `position`. Both are in
+/// timeline beats; the follower's beats are `ratio` times as fast.
+///
+/// Ticks are numbered in `ppqn` steps per timeline beat and everything is
+/// due `latency` seconds before its place on the timeline.
+///
+/// Scheduler state that sending a message changes, as it was before a
+/// message that was sent ahead of time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Checkpoint {
+    transport: Transport,
+    stop_beat: Option<f64>,
+    origin: f64,
+    position: f64,
 }
 
-fn duration_from_bpm(bpm: f64) -> Duration {
-    let nanos = (60_000_000_000_f64 / (bpm * PPQN)).max(1.0);
-    Duration::from_nanos(nanos as u64)
+/// A scheduled STOP is due at timeline beat `stop_beat`, unless the
+/// transport has stopped before; it is dropped when the clock starts or
+/// continues again.
+#[derive(Debug)]
+struct Scheduler {
+    timeline: Timeline,
+    quantum: f64,
+    follow: bool,
+    transport: Transport,
+    stop_beat: Option<f64>,
+    origin: f64,
+    position: f64,
+    ratio: f64,
+    ppqn: f64,
+    latency: f64,
 }
 
-fn sanitize_bpm(raw: f64) -> f64 {
-    raw.clamp(MIN_BPM, MAX_BPM)
+impl Scheduler {
+    fn new(bpm: f64, now: Instant, config: &ClockOutputConfig) -> Self {
+        let ratio = if config.ratio > 0.0 {
+            config.ratio
+        } else {
+            1.0
+        };
+        Self {
+            timeline: Timeline::new(bpm, 0.0, now),
+            quantum: 1.0,
+            follow: false,
+            transport: Transport::Stopped,
+            stop_beat: None,
+            origin: 0.0,
+            position: 0.0,
+            ratio,
+            ppqn: PPQN * ratio,
+            latency: config.latency_ms / 1000.0,
+        }
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            transport: self.transport,
+            stop_beat: self.stop_beat,
+            origin: self.origin,
+            position: self.position,
+        }
+    }
+
+    /// Go back to the state from before the messages that were taken back.
+    fn restore(&mut self, checkpoint: Checkpoint) {
+        self.transport = checkpoint.transport;
+        self.stop_beat = checkpoint.stop_beat;
+        self.origin = checkpoint.origin;
+        self.position = checkpoint.position;
+    }
+
+    /// When the message for timeline `beat` has to be sent.
+    fn due(&self, beat: f64) -> Instant {
+        offset(self.timeline.time_at_beat(beat), -self.latency)
+    }
+
+    /// Earliest a beat scheduled at `now` can land, so that its messages,
+    /// sent `latency` ahead, are not already late.
+    fn earliest(&self, now: Instant) -> Instant {
+        offset(now + SCHEDULE_LEAD, self.latency.max(0.0))
+    }
+
+    /// Timeline beat whose messages are due at `now`.
+    fn beat_due_at(&self, now: Instant) -> f64 {
+        self.timeline.beat_at(offset(now, self.latency))
+    }
+
+    /// Round a song position down to a whole sixteenth note of the follower.
+    fn quantize(&self, beat: f64) -> f64 {
+        let steps = SIXTEENTHS * self.ratio;
+        let max = f64::from(MAX_SONG_POSITION) / steps;
+        ((beat * steps).floor() / steps).clamp(0.0, max)
+    }
+
+    fn song_position(&self) -> Message {
+        Message::SongPosition((self.position * SIXTEENTHS * self.ratio).round() as u16)
+    }
+
+    fn is_running(&self) -> bool {
+        matches!(self.transport, Transport::Running { .. })
+    }
+
+    /// Timeline beat of the next transport message or tick, or `None`
+    /// while stopped.
+    fn next_beat(&self) -> Option<f64> {
+        match self.transport {
+            Transport::Stopped => None,
+            Transport::Starting { beat } | Transport::Continuing { beat } => Some(beat),
+            Transport::Running { tick } => Some(tick as f64 / self.ppqn),
+        }
+    }
+
+    /// Whether the scheduled STOP comes before anything else.
+    fn stop_is_next(&self) -> bool {
+        match (self.stop_beat, self.next_beat()) {
+            (Some(stop), Some(next)) => stop <= next,
+            _ => false,
+        }
+    }
+
+    /// When the next message is due, or `None` while stopped.
+    fn deadline(&self) -> Option<Instant> {
+        if self.stop_is_next() {
+            return self.stop_beat.map(|beat| self.due(beat));
+        }
+        self.next_beat().map(|beat| self.due(beat))
+    }
+
+    /// Message that is due now; advances the schedule past it.
+    fn fire(&mut self) -> Option<Message> {
+        if self.stop_is_next() {
+            self.transport = Transport::Stopped;
+            self.position = 0.0;
+            return Some(Message::Stop);
+        }
+        match self.transport {
+            Transport::Stopped => None,
+            Transport::Starting { beat } => {
+                self.origin = beat;
+                self.transport = Transport::Running {
+                    tick: (beat * self.ppqn).round() as i64,
+                };
+                Some(Message::Start)
+            }
+            Transport::Continuing { beat } => {
+                self.origin = beat - self.position;
+                self.transport = Transport::Running {
+                    tick: (beat * self.ppqn).round() as i64,
+                };
+                Some(Message::Continue)
+            }
+            Transport::Running { tick } => {
+                self.transport = Transport::Running { tick: tick + 1 };
+                Some(Message::Clock)
+            }
+        }
+    }
+
+    fn start(&mut self, now: Instant) {
+        let beat = if self.follow {
+            let quantum = self.quantum.max(f64::EPSILON);
+            (self.beat_due_at(now) / quantum).ceil() * quantum
+        } else {
+            self.timeline = Timeline::new(self.timeline.tempo, 0.0, self.earliest(now));
+            0.0
+        };
+        self.stop_beat = None;
+        self.transport = Transport::Starting { beat };
+    }
+
+    fn start_at(&mut self, at: Instant, now: Instant) {
+        let beat = if self.follow {
+            self.timeline.beat_at(at).max(self.beat_due_at(now))
+        } else {
+            let at = at.max(self.earliest(now));
+            self.timeline = Timeline::new(self.timeline.tempo, 0.0, at);
+            0.0
+        };
+        self.stop_beat = None;
+        self.transport = Transport::Starting { beat };
+    }
+
+    fn stop_at(&mut self, at: Instant) {
+        self.stop_beat = Some(self.timeline.beat_at(at));
+    }
+
+    /// Stop without sending anything, e.g. after a send error.
+    fn halt(&mut self) {
+        self.transport = Transport::Stopped;
+    }
+
+    fn stop(&mut self) -> Vec<Message> {
+        self.transport = Transport::Stopped;
+        self.position = 0.0;
+        vec![Message::Stop]
+    }
+
+    fn pause(&mut self) -> Vec<Message> {
+        match self.transport {
+            Transport::Running { tick } => {
+                // The next tick has not been sent; resume from the
+                // sixteenth note it falls into.
+                self.position = self.quantize(tick as f64 / self.ppqn - self.origin);
+            }
+            Transport::Starting { .. } => self.position = 0.0,
+            Transport::Continuing { .. } | Transport::Stopped => {}
+        }
+        self.transport = Transport::Stopped;
+        vec![Message::Stop]
+    }
+
+    fn resume(&mut self, now: Instant) -> Vec<Message> {
+        match self.transport {
+            Transport::Stopped => {
+                self.schedule_continue(self.earliest(now));
+                vec![self.song_position()]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn locate(&mut self, beat: f64, now: Instant) -> Vec<Message> {
+        let mut messages = Vec::new();
+        if self.is_running() {
+            messages.push(Message::Stop);
+        }
+        self.position = self.quantize(beat);
+        messages.push(self.song_position());
+        if self.transport != Transport::Stopped {
+            self.schedule_continue(self.earliest(now));
+        }
+        messages
+    }
+
+    /// Schedule CONTINUE from `position` no earlier than `earliest`.
+    fn schedule_continue(&mut self, earliest: Instant) {
+        let beat = if self.follow {
+            // Keep the follower's bars in phase with the timeline's.
+            let quantum = self.quantum.max(f64::EPSILON);
+            let from = self.beat_due_at(earliest);
+            self.position + ((from - self.position) / quantum).ceil() * quantum
+        } else {
+            self.timeline = Timeline::new(self.timeline.tempo, self.position, earliest);
+            self.position
+        };
+        self.stop_beat = None;
+        self.transport = Transport::Continuing { beat };
+    }
+
+    fn set_bpm(&mut self, bpm: f64, now: Instant) {
+        self.timeline = self.timeline.with_tempo(bpm, now);
+    }
+
+    fn set_timeline(&mut self, timeline: Timeline, quantum: f64, now: Instant) {
+        self.timeline = timeline;
+        self.quantum = quantum;
+        self.follow = true;
+
+        // Skip ticks the new timeline has already moved past, but never
+        // repeat one that was sent; a backwards jump just holds the clock.
+        if let Transport::Running { tick } = self.transport {
+            let current = (self.beat_due_at(now) * self.ppqn).floor() as i64;
+            self.transport = Transport::Running {
+                tick: tick.max(current),
+            };
+        }
+    }
+
+    fn shift_phase(&mut self, beats: f64) {
+        let timeline = self.timeline;
+        self.timeline = Timeline::new(timeline.tempo, timeline.beat + beats, timeline.at);
+    }
+}
+
+/// `instant` moved by a signed number of seconds.
+fn offset(instant: Instant, seconds: f64) -> Instant {
+    let Ok(shift) = Duration::try_from_secs_f64(seconds.abs()) else {
+        return instant;
+    };
+    let shifted = if seconds >= 0.0 {
+        instant.checked_add(shift)
+    } else {
+        instant.checked_sub(shift)
+    };
+    shifted.unwrap_or(instant)
+}
+
+fn send_message(connection: &mut MidiOutputConnection, message: Message) -> Result<(), SendError> {
+    match message {
+        Message::Clock => connection.send(&[0xF8]),
+        Message::Start => connection.send(&[0xFA]),
+        Message::Continue => connection.send(&[0xFB]),
+        Message::Stop => connection.send(&[0xFC]),
+        Message::SongPosition(position) => {
+            connection.send(&[0xF2, (position & 0x7F) as u8, (position >> 7) as u8 & 0x7F])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    const TICK_120: Duration = Duration::from_nanos(20_833_333);
+
+    fn close(a: Instant, b: Instant) -> bool {
+        let diff = if a > b { a - b } else { b - a };
+        diff < Duration::from_micros(1)
+    }
+
+    fn scheduler(now: Instant) -> Scheduler {
+        Scheduler::new(120.0, now, &ClockOutputConfig::new(PortSelector::Index(0)))
+    }
+
+    #[test]
+    fn free_running_start_follows_the_lead() {
+        let now = Instant::now();
+        let mut scheduler = scheduler(now);
+        assert_eq!(scheduler.deadline(), None);
+
+        scheduler.start(now);
+        let start = now + SCHEDULE_LEAD;
+        assert!(close(scheduler.deadline().unwrap(), start));
+        assert_eq!(scheduler.fire(), Some(Message::Start));
+        assert!(close(scheduler.deadline().unwrap(), start));
+        assert_eq!(scheduler.fire(), Some(Message::Clock));
+        assert!(close(scheduler.deadline().unwrap(), start + TICK_120));
+    }
+
+    #[test]
+    fn tempo_change_does_not_restart_the_phase() {
+        let now = Instant::now();
+        let mut scheduler = scheduler(now);
+        scheduler.start(now);
+        scheduler.fire();
+        scheduler.fire();
+
+        // Halfway to the next tick, doubling the tempo halves the remaining wait.
+        let start = now + SCHEDULE_LEAD;
+        scheduler.set_bpm(240.0, start + TICK_120 / 2);
+        assert!(close(
+            scheduler.deadline().unwrap(),
+            start + TICK_120 * 3 / 4
+        ));
+    }
+
+    #[test]
+    fn ratio_and_latency_shift_the_ticks() {
+        let now = Instant::now();
+        let timeline = Timeline::new(120.0, 0.0, now);
+        let config = ClockOutputConfig {
+            latency_ms: 10.0,
+            ratio: 2.0,
+            ..ClockOutputConfig::new(PortSelector::Index(0))
+        };
+        let mut scheduler = Scheduler::new(120.0, now, &config);
+        scheduler.set_timeline(timeline, 4.0, now);
+
+        scheduler.start(now);
+        assert_eq!(scheduler.transport, Transport::Starting { beat: 4.0 });
+        let bar = timeline.time_at_beat(4.0);
+        let early = bar - Duration::from_millis(10);
+        assert!(close(scheduler.deadline().unwrap(), early));
+        scheduler.fire();
+        scheduler.fire();
+        assert!(close(scheduler.deadline().unwrap(), early + TICK_120 / 2));
+
+        // Song positions count the follower's (double-time) sixteenths.
+        scheduler.pause();
+        assert_eq!(scheduler.locate(1.2, now), vec![Message::SongPosition(9)]);
+    }
+
+    #[test]
+    fn follow_mode_starts_on_the_next_bar() {
+        let now = Instant::now();
+        let mut scheduler = scheduler(now);
+        let timeline = Timeline::new(120.0, 5.5, now);
+        scheduler.set_timeline(timeline, 4.0, now);
+
+        scheduler.start(now);
+        assert_eq!(scheduler.transport, Transport::Starting { beat: 8.0 });
+        assert!(close(
+            scheduler.deadline().unwrap(),
+            timeline.time_at_beat(8.0)
+        ));
+        assert_eq!(scheduler.fire(), Some(Message::Start));
+        assert_eq!(scheduler.transport, Transport::Running { tick: 192 });
+    }
+
+    #[test]
+    fn timeline_updates_neither_repeat_nor_drop_due_ticks() {
+        let now = Instant::now();
+        let mut scheduler = scheduler(now);
+        scheduler.set_timeline(Timeline::new(120.0, 0.0, now), 4.0, now);
+        scheduler.start(now);
+        scheduler.fire();
+        scheduler.fire();
+        assert_eq!(scheduler.transport, Transport::Running { tick: 1 });
+
+        // Slightly late: tick 1 is still due.
+        let late = now + TICK_120 + Duration::from_micros(100);
+        scheduler.set_timeline(Timeline::new(120.0, 0.0, now), 4.0, late);
+        assert_eq!(scheduler.transport, Transport::Running { tick: 1 });
+
+        // A peer pulled the grid back: hold instead of repeating ticks.
+        scheduler.set_timeline(Timeline::new(120.0, -1.0, now), 4.0, late);
+        assert_eq!(scheduler.transport, Transport::Running { tick: 1 });
+
+        // Jumped a beat ahead: continue from the current position.
+        scheduler.set_timeline(Timeline::new(120.0, 1.0, now), 4.0, late);
+        assert_eq!(scheduler.transport, Transport::Running { tick: 25 });
+    }
+
+    #[test]
+    fn phase_shifts_keep_followers_in_phase() {
+        let now = Instant::now();
+        let mut scheduler = scheduler(now);
+        scheduler.set_timeline(Timeline::new(120.0, 0.0, now), 4.0, now);
+        scheduler.start(now);
+        scheduler.fire();
+        scheduler.fire();
+        assert_eq!(scheduler.transport, Transport::Running { tick: 1 });
+
+        // Ahead by a quarter beat: ticks 1 to 6 are due at once.
+        scheduler.shift_phase(0.25);
+        assert_eq!(scheduler.transport, Transport::Running { tick: 1 });
+        assert!(close(
+            scheduler.deadline().unwrap(),
+            now + TICK_120 - TICK_120 * 6
+        ));
+
+        // Back again: tick 1 waits for its original time.
+        scheduler.shift_phase(-0.25);
+        assert!(close(scheduler.deadline().unwrap(), now + TICK_120));
+    }
+
+    #[test]
+    fn latency_does_not_bunch_up_the_first_ticks() {
+        let now = Instant::now();
+        let config = ClockOutputConfig {
+            latency_ms: 200.0,
+            ..ClockOutputConfig::new(PortSelector::Index(0))
+        };
+        let mut scheduler = Scheduler::new(120.0, now, &config);
+
+        // Messages go out when due, or right away if they are late.
+        let assert_spaced = |scheduler: &mut Scheduler| {
+            let times: Vec<Instant> = (0..12)
+                .map(|_| {
+                    let sent = scheduler.deadline().unwrap().max(now);
+                    scheduler.fire();
+                    sent
+                })
+                .collect();
+            for pair in times[1..].windows(2) {
+                assert!(pair[1] - pair[0] >= TICK_120 - Duration::from_micros(1));
+            }
+        };
+
+        scheduler.start(now);
+        assert!(close(scheduler.deadline().unwrap(), now + SCHEDULE_LEAD));
+        assert_spaced(&mut scheduler);
+
+        scheduler.pause();
+        scheduler.resume(now);
+        assert!(close(scheduler.deadline().unwrap(), now + SCHEDULE_LEAD));
+        assert_spaced(&mut scheduler);
+    }
+
+    #[test]
+    fn scheduled_start_and_stop_land_on_their_beats() {
+        let now = Instant::now();
+        let mut scheduler = scheduler(now);
+        let timeline = Timeline::new(120.0, -4.0, now);
+        scheduler.set_timeline(timeline, 4.0, now);
+
+        // A one-bar count-in: START on beat 0, two seconds from now.
+        scheduler.start_at(timeline.time_at_beat(0.0), now);
+        assert_eq!(scheduler.transport, Transport::Starting { beat: 0.0 });
+        assert_eq!(scheduler.fire(), Some(Message::Start));
+
+        // STOP replaces the tick on the next downbeat.
+        scheduler.stop_at(timeline.time_at_beat(4.0));
+        let mut ticks = 0;
+        while scheduler.deadline().unwrap() < timeline.time_at_beat(4.0) {
+            assert_eq!(scheduler.fire(), Some(Message::Clock));
+            ticks += 1;
+        }
+        assert_eq!(ticks, 96);
+        assert!(close(
+            scheduler.deadline().unwrap(),
+            timeline.time_at_beat(4.0)
+        ));
+        assert_eq!(scheduler.fire(), Some(Message::Stop));
+        assert_eq!(scheduler.deadline(), None);
+
+        // Starting again drops the old stop.
+        scheduler.start(now);
+        assert_eq!(scheduler.stop_beat, None);
+    }
+
+    #[test]
+    fn start_on_a_downbeat_just_passed_is_not_a_bar_late() {
+        // An external START put beat 0 on the timeline a moment ago.
+        let downbeat = Instant::now();
+        let now = downbeat + Duration::from_millis(5);
+        let mut scheduler = scheduler(now);
+        scheduler.set_timeline(Timeline::new(120.0, 0.0, downbeat), 4.0, now);
+
+        scheduler.start_at(downbeat, now);
+        assert!(close(scheduler.deadline().unwrap(), now));
+        assert_eq!(scheduler.fire(), Some(Message::Start));
+        assert_eq!(scheduler.transport, Transport::Running { tick: 0 });
+    }
+
+    #[test]
+    fn cancelled_stop_keeps_the_clock_running() {
+        let now = Instant::now();
+        let mut scheduler = scheduler(now);
+        scheduler.set_timeline(Timeline::new(120.0, 0.0, now), 4.0, now);
+        scheduler.start(now);
+        scheduler.fire();
+        scheduler.stop_at(now + TICK_120 * 2);
+        scheduler.stop_beat = None;
+
+        for tick in 0..4 {
+            assert_eq!(scheduler.transport, Transport::Running { tick });
+            assert_eq!(scheduler.fire(), Some(Message::Clock));
+        }
+    }
+
+    /// Sink that holds messages until their time, like a sequencer queue.
+    #[derive(Clone, Default)]
+    struct QueueSink(Arc<Mutex<Vec<(Message, Instant)>>>);
+
+    impl ClockSink for QueueSink {
+        fn lookahead(&self) -> Duration {
+            Duration::from_millis(100)
+        }
+
+        fn send(&mut self, message: Message, at: Instant) -> Result<(), Box<dyn Error>> {
+            self.0.lock().unwrap().push((message, at));
+            Ok(())
+        }
+
+        fn revoke(&mut self, after: Instant) -> Result<(), Box<dyn Error>> {
+            self.0.lock().unwrap().retain(|&(_, at)| at <= after);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn queued_ticks_are_revised_on_tempo_changes() {
+        let sink = QueueSink::default();
+        let config = ClockOutputConfig::new(PortSelector::Index(0));
+        let clock = MidiClock::spawn(sink.clone(), &config, 120.0, "queue".into()).unwrap();
+        clock.start().unwrap();
+        thread::sleep(Duration::from_millis(150));
+        clock.set_bpm(240.0).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        drop(clock);
+
+        let sent = sink.0.lock().unwrap().clone();
+        assert_eq!(sent.first().map(|m| m.0), Some(Message::Start));
+        assert_eq!(sent.last().map(|m| m.0), Some(Message::Stop));
+
+        // Ticks queued past the change were replaced, not duplicated or dropped.
+        let ticks: Vec<Instant> = sent
+            .iter()
+            .filter(|m| m.0 == Message::Clock)
+            .map(|m| m.1)
+            .collect();
+        assert!(ticks.len() > 10);
+        for pair in ticks.windows(2) {
+            let gap = pair[1] - pair[0];
+            assert!(gap >= TICK_120 / 2 - Duration::from_micros(10), "{gap:?}");
+            assert!(gap <= TICK_120 + Duration::from_micros(10), "{gap:?}");
+        }
+    }
+
+    #[test]
+    fn cancelling_a_queued_stop_takes_it_back() {
+        let sink = QueueSink::default();
+        let config = ClockOutputConfig::new(PortSelector::Index(0));
+        let clock = MidiClock::spawn(sink.clone(), &config, 120.0, "queue".into()).unwrap();
+        let start = Instant::now();
+        clock.start().unwrap();
+        clock.stop_at(start + Duration::from_millis(200)).unwrap();
+        // The STOP is queued by now, well ahead of its time.
+        thread::sleep(Duration::from_millis(150));
+        clock.cancel_stop().unwrap();
+        thread::sleep(Duration::from_millis(150));
+        drop(clock);
+
+        let sent = sink.0.lock().unwrap().clone();
+        let stops: Vec<Instant> = sent
+            .iter()
+            .filter(|m| m.0 == Message::Stop)
+            .map(|m| m.1)
+            .collect();
+        assert_eq!(stops.len(), 1, "{sent:?}");
+        assert!(stops[0] > start + Duration::from_millis(250));
+        assert!(sent
+            .iter()
+            .any(|m| m.0 == Message::Clock && m.1 > start + Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn pausing_after_a_cancelled_stop_keeps_the_song_position() {
+        let sink = QueueSink::default();
+        let config = ClockOutputConfig::new(PortSelector::Index(0));
+        let clock = MidiClock::spawn(sink.clone(), &config, 120.0, "queue".into()).unwrap();
+        let start = Instant::now();
+        clock.start().unwrap();
+        clock.stop_at(start + Duration::from_millis(200)).unwrap();
+        thread::sleep(Duration::from_millis(150));
+        clock.cancel_stop().unwrap();
+        thread::sleep(Duration::from_millis(150));
+        clock.pause().unwrap();
+        clock.resume().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        drop(clock);
+
+        // The song position picks up right after the last tick before the
+        // pause, as if the STOP had never been queued.
+        let sent = sink.0.lock().unwrap().clone();
+        let ticks = sent
+            .iter()
+            .take_while(|m| m.0 != Message::Stop)
+            .filter(|m| m.0 == Message::Clock)
+            .count();
+        let position = sent.iter().find_map(|m| match m.0 {
+            Message::SongPosition(position) => Some(usize::from(position)),
+            _ => None,
+        });
+        assert!(ticks > 6, "{sent:?}");
+        assert_eq!(position, Some(ticks / 6), "{sent:?}");
+    }
+
+    #[test]
+    fn pause_and_resume_continue_from_the_song_position() {
+        let now = Instant::now();
+        let mut scheduler = scheduler(now);
+        scheduler.start(now);
+        scheduler.fire();
+        // Send ticks up to and including beat 2 and a bit.
+        for _ in 0..50 {
+            scheduler.fire();
+        }
+
+        assert_eq!(scheduler.pause(), vec![Message::Stop]);
+        assert_eq!(scheduler.deadline(), None);
+
+        let later = now + Duration::from_secs(10);
+        assert_eq!(scheduler.resume(later), vec![Message::SongPosition(8)]);
+        assert!(close(scheduler.deadline().unwrap(), later + SCHEDULE_LEAD));
+        assert_eq!(scheduler.fire(), Some(Message::Continue));
+        assert_eq!(scheduler.transport, Transport::Running { tick: 48 });
+        assert_eq!(scheduler.fire(), Some(Message::Clock));
+
+        // A full stop forgets the position.
+        scheduler.stop();
+        assert_eq!(scheduler.resume(later), vec![Message::SongPosition(0)]);
+    }
+
+    #[test]
+    fn locate_while_running_stops_and_continues() {
+        let now = Instant::now();
+        let mut scheduler = scheduler(now);
+        scheduler.start(now);
+        scheduler.fire();
+
+        assert_eq!(
+            scheduler.locate(16.3, now),
+            vec![Message::Stop, Message::SongPosition(65)]
+        );
+        assert_eq!(scheduler.fire(), Some(Message::Continue));
+        assert_eq!(scheduler.transport, Transport::Running { tick: 390 });
+
+        // While stopped only the pointer is sent.
+        scheduler.pause();
+        assert_eq!(scheduler.locate(0.0, now), vec![Message::SongPosition(0)]);
+        assert_eq!(scheduler.deadline(), None);
+    }
+
+    #[test]
+    fn follow_mode_resumes_in_phase_with_the_bars() {
+        let now = Instant::now();
+        let mut scheduler = scheduler(now);
+        let timeline = Timeline::new(120.0, 9.0, now);
+        scheduler.set_timeline(timeline, 4.0, now);
+        scheduler.locate(1.0, now);
+
+        assert_eq!(scheduler.resume(now), vec![Message::SongPosition(4)]);
+        assert_eq!(scheduler.transport, Transport::Continuing { beat: 13.0 });
+        scheduler.fire();
+        assert_eq!(scheduler.origin, 12.0);
+    }
 }
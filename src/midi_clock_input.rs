@@ -0,0 +1,266 @@
+//! MIDI clock input: follow an external 24 PPQN clock master.
+
+use crate::midi_clock::{MidiClockError, PortSelector};
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use std::{
+    collections::VecDeque,
+    mem,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+const PPQN: usize = 24;
+/// Without a tick for this long the external clock counts as gone.
+const CLOCK_TIMEOUT: Duration = Duration::from_millis(500);
+/// Weight of a new one-beat tempo measurement in the smoothed tempo.
+const TEMPO_SMOOTHING: f64 = 0.2;
+/// Relative tempo jump that is followed immediately instead of smoothed.
+const TEMPO_JUMP: f64 = 0.08;
+/// Smallest tempo change, in BPM, that is reported again.
+const REPORT_THRESHOLD: f64 = 0.05;
+
+const CLOCK: u8 = 0xF8;
+const START: u8 = 0xFA;
+const CONTINUE: u8 = 0xFB;
+const STOP: u8 = 0xFC;
+
+/// Something the external clock master did.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockInputEvent {
+    /// The smoothed tempo changed; the first one means the clock has locked.
+    Tempo(f64),
+    Start,
+    Continue,
+    Stop,
+    /// Clock ticks stopped arriving; the lock is released.
+    Lost,
+}
+
+/// MIDI clock input on a single port.
+///
+/// Incoming messages are handled on the MIDI backend's thread; call
+/// [`poll_events`](Self::poll_events) from the application loop to pick up
+/// tempo and transport changes.
+pub struct MidiClockInput {
+    _connection: MidiInputConnection<()>,
+    follower: Arc<Mutex<ClockFollower>>,
+    port_name: String,
+}
+
+impl MidiClockInput {
+    /// Open the first input port whose name contains `port_hint`
+    /// (case-insensitive), or the first port if the hint is empty.
+    pub fn new(port_hint: &str) -> Result<Self, MidiClockError> {
+        let mut midi_in = MidiInput::new("x1-tap-clock")
+            .map_err(|err| MidiClockError::MidiInit(err.to_string()))?;
+        // Clock and transport are system real-time messages, which midir
+        // drops by default.
+        midi_in.ignore(Ignore::SysexAndActiveSense);
+
+        let port = PortSelector::Name(port_hint.into())
+            .find(&midi_in)
+            .ok_or_else(|| MidiClockError::PortNotFound(port_hint.to_string()))?;
+        let port_name = midi_in
+            .port_name(&port)
+            .unwrap_or_else(|_| "<unknown>".into());
+
+        let follower = Arc::new(Mutex::new(ClockFollower::default()));
+        let shared = Arc::clone(&follower);
+        let connection = midi_in
+            .connect(
+                &port,
+                "x1-tap-clock-in",
+                move |stamp, message, _| {
+                    if let Ok(mut follower) = shared.lock() {
+                        follower.on_message(stamp, message, Instant::now());
+                    }
+                },
+                (),
+            )
+            .map_err(|err| MidiClockError::Connection(err.to_string()))?;
+
+        Ok(Self {
+            _connection: connection,
+            follower,
+            port_name,
+        })
+    }
+
+    /// Name of the connected input port.
+    pub fn port_name(&self) -> &str {
+        &self.port_name
+    }
+
+    /// Whether a steady clock is currently being received.
+    pub fn is_locked(&self) -> bool {
+        self.follower
+            .lock()
+            .map(|follower| follower.is_locked())
+            .unwrap_or(false)
+    }
+
+    /// Events received since the last call.
+    pub fn poll_events(&self) -> Vec<ClockInputEvent> {
+        self.follower
+            .lock()
+            .map(|mut follower| follower.poll(Instant::now()))
+            .unwrap_or_default()
+    }
+}
+
+/// Tempo estimation and transport tracking for an incoming clock.
+///
+/// The tempo is measured over the last full beat of ticks and then smoothed,
+/// so per-tick jitter of the clock source and the MIDI driver averages out.
+#[derive(Debug, Default)]
+struct ClockFollower {
+    /// Timestamps (µs) of up to one beat plus one of recent ticks.
+    ticks: VecDeque<u64>,
+    tempo: Option<f64>,
+    reported: Option<f64>,
+    last_tick: Option<Instant>,
+    events: Vec<ClockInputEvent>,
+}
+
+impl ClockFollower {
+    fn is_locked(&self) -> bool {
+        self.reported.is_some()
+    }
+
+    fn on_message(&mut self, stamp: u64, message: &[u8], received: Instant) {
+        match message.first() {
+            Some(&CLOCK) => self.on_tick(stamp, received),
+            Some(&START) => self.events.push(ClockInputEvent::Start),
+            Some(&CONTINUE) => self.events.push(ClockInputEvent::Continue),
+            Some(&STOP) => self.events.push(ClockInputEvent::Stop),
+            _ => {}
+        }
+    }
+
+    fn on_tick(&mut self, stamp: u64, received: Instant) {
+        let stalled = self
+            .last_tick
+            .is_some_and(|last| received.duration_since(last) > CLOCK_TIMEOUT);
+        if stalled || self.ticks.back().is_some_and(|&last| stamp <= last) {
+            self.ticks.clear();
+        }
+        self.last_tick = Some(received);
+        self.ticks.push_back(stamp);
+        if self.ticks.len() > PPQN + 1 {
+            self.ticks.pop_front();
+        }
+        if self.ticks.len() <= PPQN {
+            return;
+        }
+
+        let (Some(&first), Some(&last)) = (self.ticks.front(), self.ticks.back()) else {
+            return;
+        };
+        let measured = 60_000_000.0 / (last - first) as f64;
+        let tempo = match self.tempo {
+            Some(tempo) if (measured - tempo).abs() <= tempo * TEMPO_JUMP => {
+                tempo + (measured - tempo) * TEMPO_SMOOTHING
+            }
+            _ => measured,
+        };
+        self.tempo = Some(tempo);
+
+        let changed = match self.reported {
+            Some(reported) => (tempo - reported).abs() >= REPORT_THRESHOLD,
+            None => true,
+        };
+        if changed {
+            self.reported = Some(tempo);
+            self.events.push(ClockInputEvent::Tempo(tempo));
+        }
+    }
+
+    fn poll(&mut self, now: Instant) -> Vec<ClockInputEvent> {
+        let timed_out = self
+            .last_tick
+            .is_some_and(|last| now.saturating_duration_since(last) > CLOCK_TIMEOUT);
+        if timed_out {
+            self.ticks.clear();
+            self.last_tick = None;
+            self.tempo = None;
+            if self.reported.take().is_some() {
+                self.events.push(ClockInputEvent::Lost);
+            }
+        }
+        mem::take(&mut self.events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `count` ticks at `bpm`, offsetting every other one by `jitter` µs.
+    fn feed(
+        follower: &mut ClockFollower,
+        start: (u64, Instant),
+        count: u64,
+        bpm: f64,
+        jitter: u64,
+    ) -> (u64, Instant) {
+        let interval = (60_000_000.0 / bpm / PPQN as f64) as u64;
+        let (mut stamp, mut at) = start;
+        for i in 0..count {
+            let offset = if i % 2 == 1 { jitter } else { 0 };
+            follower.on_message(stamp + offset, &[CLOCK], at);
+            stamp += interval;
+            at += Duration::from_micros(interval);
+        }
+        (stamp, at)
+    }
+
+    #[test]
+    fn locks_after_one_beat_despite_jitter() {
+        let mut follower = ClockFollower::default();
+        let now = Instant::now();
+
+        let position = feed(&mut follower, (0, now), PPQN as u64, 120.0, 1_500);
+        assert!(!follower.is_locked());
+        assert!(follower.poll(position.1).is_empty());
+
+        let position = feed(&mut follower, position, 4 * PPQN as u64, 120.0, 1_500);
+        assert!(follower.is_locked());
+        let events = follower.poll(position.1);
+        let Some(ClockInputEvent::Tempo(bpm)) = events.last() else {
+            panic!("expected a tempo event, got {events:?}");
+        };
+        assert!((bpm - 120.0).abs() < 0.1, "{bpm}");
+    }
+
+    #[test]
+    fn follows_tempo_jumps_and_reports_transport() {
+        let mut follower = ClockFollower::default();
+        let now = Instant::now();
+        follower.on_message(0, &[START], now);
+        let position = feed(&mut follower, (0, now), 2 * PPQN as u64, 100.0, 0);
+        let position = feed(&mut follower, position, 2 * PPQN as u64, 140.0, 0);
+        follower.on_message(position.0, &[STOP], position.1);
+
+        let events = follower.poll(position.1);
+        assert_eq!(events.first(), Some(&ClockInputEvent::Start));
+        assert_eq!(events.last(), Some(&ClockInputEvent::Stop));
+        let Some(ClockInputEvent::Tempo(bpm)) = events.iter().rev().nth(1) else {
+            panic!("expected a tempo event, got {events:?}");
+        };
+        assert!((bpm - 140.0).abs() < 0.1, "{bpm}");
+    }
+
+    #[test]
+    fn releases_the_lock_when_ticks_stop() {
+        let mut follower = ClockFollower::default();
+        let now = Instant::now();
+        let position = feed(&mut follower, (0, now), 2 * PPQN as u64, 120.0, 0);
+        follower.poll(position.1);
+        assert!(follower.is_locked());
+
+        let later = position.1 + CLOCK_TIMEOUT * 2;
+        assert_eq!(follower.poll(later), vec![ClockInputEvent::Lost]);
+        assert!(!follower.is_locked());
+        assert!(follower.poll(later).is_empty());
+    }
+}
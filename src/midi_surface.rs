@@ -0,0 +1,554 @@
+//! Virtual MIDI controller mode: the X1 as a plain MIDI surface.
+//!
+//! Every button sends note on/off, every pot a control change (optionally
+//! 14-bit) and every encoder a relative control change on a virtual output
+//! port, so DAWs and other tools can MIDI-learn the controller directly.
+//! Notes and CCs sent back to the matching virtual input port light the
+//! buttons' LEDs.
+
+use std::{
+    mem,
+    sync::{Arc, Mutex},
+};
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use serde::Deserialize;
+
+use crate::midi_clock::MidiClockError;
+use crate::x1_controller::{
+    ButtonEvent, ButtonEventKind, ButtonId, EncoderEvent, EncoderId, LedId, PotEvent, PotId,
+    LED_BRIGHT, LED_DIM,
+};
+
+/// Note sent by the first button when no note is configured.
+const FIRST_BUTTON_NOTE: u8 = 36;
+/// Controller number of the first pot when none is configured.
+const FIRST_POT_CC: u8 = 16;
+/// Controller number of the first encoder when none is configured.
+const FIRST_ENCODER_CC: u8 = 80;
+/// Relative encoder value meaning "no movement" (binary offset encoding).
+const RELATIVE_CENTER: i32 = 64;
+/// 14-bit controllers send their low byte on `cc + LSB_OFFSET`.
+const LSB_OFFSET: u8 = 32;
+
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const CONTROL_CHANGE: u8 = 0xB0;
+
+/// Note and controller numbers of the virtual MIDI surface (`midi_surface`
+/// in the mapping file).
+///
+/// Controls without an entry get consecutive defaults on `channel`: buttons
+/// from note 36, pots from CC 16 and encoders from CC 80, each in
+/// declaration order.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SurfaceConfig {
+    #[serde(default = "default_port_name")]
+    pub port_name: String,
+    /// Default MIDI channel, 1–16.
+    #[serde(default = "default_channel")]
+    pub channel: u8,
+    #[serde(default)]
+    pub buttons: Vec<ButtonNote>,
+    #[serde(default)]
+    pub pots: Vec<PotControl>,
+    #[serde(default)]
+    pub encoders: Vec<EncoderControl>,
+}
+
+impl Default for SurfaceConfig {
+    fn default() -> Self {
+        Self {
+            port_name: default_port_name(),
+            channel: default_channel(),
+            buttons: Vec::new(),
+            pots: Vec::new(),
+            encoders: Vec::new(),
+        }
+    }
+}
+
+fn default_port_name() -> String {
+    "X1 Surface".into()
+}
+
+fn default_channel() -> u8 {
+    1
+}
+
+/// Note sent by a button.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ButtonNote {
+    pub button: ButtonId,
+    #[serde(default)]
+    pub channel: Option<u8>,
+    pub note: u8,
+}
+
+/// Control change sent by a pot. With `high_resolution` the pot sends a
+/// 14-bit value as MSB on `cc` and LSB on `cc + 32`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PotControl {
+    pub pot: PotId,
+    #[serde(default)]
+    pub channel: Option<u8>,
+    pub cc: u8,
+    #[serde(default)]
+    pub high_resolution: bool,
+}
+
+/// Relative control change sent by an encoder.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncoderControl {
+    pub encoder: EncoderId,
+    #[serde(default)]
+    pub channel: Option<u8>,
+    pub cc: u8,
+}
+
+/// A resolved note or controller: zero-based channel and number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Control {
+    pub channel: u8,
+    pub number: u8,
+}
+
+impl SurfaceConfig {
+    /// Note played by `id`.
+    pub fn button(&self, id: ButtonId) -> Control {
+        match self.buttons.iter().find(|b| b.button == id) {
+            Some(entry) => self.control(entry.channel, entry.note),
+            None => self.control(None, FIRST_BUTTON_NOTE + position(&ButtonId::ALL, id)),
+        }
+    }
+
+    /// Controller sent by `id`, and whether it is 14-bit.
+    pub fn pot(&self, id: PotId) -> (Control, bool) {
+        match self.pots.iter().find(|p| p.pot == id) {
+            Some(entry) => (self.control(entry.channel, entry.cc), entry.high_resolution),
+            None => (self.control(None, FIRST_POT_CC + id.index() as u8), false),
+        }
+    }
+
+    /// Controller sent by `id`.
+    pub fn encoder(&self, id: EncoderId) -> Control {
+        match self.encoders.iter().find(|e| e.encoder == id) {
+            Some(entry) => self.control(entry.channel, entry.cc),
+            None => self.control(None, FIRST_ENCODER_CC + position(&EncoderId::ALL, id)),
+        }
+    }
+
+    /// Check channels and note/controller numbers.
+    pub fn validate(&self) -> Result<(), String> {
+        let channels = self
+            .buttons
+            .iter()
+            .map(|b| b.channel)
+            .chain(self.pots.iter().map(|p| p.channel))
+            .chain(self.encoders.iter().map(|e| e.channel))
+            .flatten()
+            .chain([self.channel]);
+        for channel in channels {
+            if !(1..=16).contains(&channel) {
+                return Err(format!("midi channel {channel} must be 1-16"));
+            }
+        }
+        for button in &self.buttons {
+            if button.note > 127 {
+                return Err(format!(
+                    "note {} of {:?} must be 0-127",
+                    button.note, button.button
+                ));
+            }
+        }
+        for pot in &self.pots {
+            let limit = if pot.high_resolution { 31 } else { 127 };
+            if pot.cc > limit {
+                return Err(format!("cc {} of {:?} must be 0-{limit}", pot.cc, pot.pot));
+            }
+        }
+        for encoder in &self.encoders {
+            if encoder.cc > 127 {
+                return Err(format!(
+                    "cc {} of {:?} must be 0-127",
+                    encoder.cc, encoder.encoder
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Note on (velocity 127) for a press, note off for a release.
+    pub fn button_message(&self, event: &ButtonEvent) -> [u8; 3] {
+        let control = self.button(event.id);
+        match event.kind {
+            ButtonEventKind::Pressed => [NOTE_ON | control.channel, control.number, 127],
+            ButtonEventKind::Released => [NOTE_OFF | control.channel, control.number, 0],
+        }
+    }
+
+    /// One control change for the normalized pot position, or an MSB/LSB
+    /// pair for a 14-bit pot.
+    pub fn pot_messages(&self, event: &PotEvent) -> Vec<[u8; 3]> {
+        let (control, high_resolution) = self.pot(event.id);
+        let status = CONTROL_CHANGE | control.channel;
+        let position = f64::from(event.normalized).clamp(0.0, 1.0);
+        if high_resolution {
+            let value = (position * 16383.0).round() as u16;
+            vec![
+                [status, control.number, (value >> 7) as u8],
+                [status, control.number + LSB_OFFSET, (value & 0x7F) as u8],
+            ]
+        } else {
+            vec![[status, control.number, (position * 127.0).round() as u8]]
+        }
+    }
+
+    /// Relative control change in binary offset encoding (64 + detents,
+    /// clamped to 1–127), or `None` if the encoder did not move.
+    pub fn encoder_message(&self, event: &EncoderEvent) -> Option<[u8; 3]> {
+        if event.accelerated_delta == 0 {
+            return None;
+        }
+        let control = self.encoder(event.id);
+        let value = (RELATIVE_CENTER + event.accelerated_delta).clamp(1, 127) as u8;
+        Some([CONTROL_CHANGE | control.channel, control.number, value])
+    }
+
+    /// LED and brightness for an incoming note or control change addressed
+    /// to a button: note velocity or CC value 0–127 is scaled onto
+    /// `LED_DIM..=LED_BRIGHT`, note off is dim.
+    pub fn led_level(&self, message: &[u8]) -> Option<(LedId, u8)> {
+        let [status, number, value] = *message else {
+            return None;
+        };
+        let value = match status & 0xF0 {
+            NOTE_OFF => 0,
+            NOTE_ON | CONTROL_CHANGE => value & 0x7F,
+            _ => return None,
+        };
+        let target = Control {
+            channel: status & 0x0F,
+            number,
+        };
+        let led = ButtonId::ALL
+            .into_iter()
+            .find(|&id| self.button(id) == target)
+            .and_then(ButtonId::led)?;
+        let range = u16::from(LED_BRIGHT - LED_DIM);
+        Some((led, LED_DIM + (range * u16::from(value) / 127) as u8))
+    }
+
+    fn control(&self, channel: Option<u8>, number: u8) -> Control {
+        Control {
+            channel: channel.unwrap_or(self.channel).clamp(1, 16) - 1,
+            number: number & 0x7F,
+        }
+    }
+}
+
+fn position<T: PartialEq>(all: &[T], id: T) -> u8 {
+    all.iter()
+        .position(|candidate| *candidate == id)
+        .unwrap_or(0) as u8
+}
+
+/// A virtual MIDI output port carrying the X1's controls.
+pub struct MidiSurface {
+    connection: MidiOutputConnection,
+    config: SurfaceConfig,
+    /// Last messages sent per pot (both halves of a 14-bit value), to drop
+    /// repeats after rounding.
+    last_pot: [Vec<[u8; 3]>; PotId::ALL.len()],
+}
+
+impl MidiSurface {
+    /// Create the virtual output port named in `config`.
+    pub fn open(config: SurfaceConfig) -> Result<Self, MidiClockError> {
+        let midi_out = MidiOutput::new("x1-tap-clock")
+            .map_err(|err| MidiClockError::MidiInit(err.to_string()))?;
+        let connection = create_virtual(midi_out, &config.port_name)?;
+        Ok(Self {
+            connection,
+            config,
+            last_pot: Default::default(),
+        })
+    }
+
+    /// Name of the virtual port.
+    pub fn port_name(&self) -> &str {
+        &self.config.port_name
+    }
+
+    pub fn send_button(&mut self, event: &ButtonEvent) {
+        let message = self.config.button_message(event);
+        let _ = self.connection.send(&message);
+    }
+
+    pub fn send_pot(&mut self, event: &PotEvent) {
+        let messages = self.config.pot_messages(event);
+        let last = &mut self.last_pot[event.id.index()];
+        if *last == messages {
+            return;
+        }
+        for message in &messages {
+            let _ = self.connection.send(message);
+        }
+        *last = messages;
+    }
+
+    pub fn send_encoder(&mut self, event: &EncoderEvent) {
+        if let Some(message) = self.config.encoder_message(event) {
+            let _ = self.connection.send(&message);
+        }
+    }
+}
+
+/// Latest LED level per LED, waiting to be picked up by the polling loop.
+type PendingLeds = [Option<u8>; LedId::ALL.len()];
+
+/// A virtual MIDI input port whose notes and CCs drive the X1's LEDs.
+///
+/// Messages arrive on the MIDI backend's thread and only update a shared
+/// table; the thread that owns the controller picks the changes up with
+/// [`poll`](Self::poll), so LED writes never race the polling loop. Several
+/// messages for the same LED between two polls collapse into the latest one.
+///
+/// The levels belong on [`Layer::Base`](crate::x1_controller::Layer::Base)
+/// of an [`LedCompositor`](crate::x1_controller::LedCompositor), so taps,
+/// the beat and other effects still show on top of them; written into the
+/// controller's LED frame directly, they would be overwritten by the next
+/// rendered frame.
+pub struct MidiFeedback {
+    _connection: MidiInputConnection<()>,
+    pending: Arc<Mutex<PendingLeds>>,
+}
+
+impl MidiFeedback {
+    /// Create the virtual input port named in `config`; incoming messages
+    /// are matched against the same notes as [`MidiSurface`] sends.
+    pub fn open(config: SurfaceConfig) -> Result<Self, MidiClockError> {
+        let midi_in = MidiInput::new("x1-tap-clock")
+            .map_err(|err| MidiClockError::MidiInit(err.to_string()))?;
+        let pending = Arc::new(Mutex::new([None; LedId::ALL.len()]));
+        let shared = Arc::clone(&pending);
+        let port_name = config.port_name.clone();
+        let connection = create_virtual_input(midi_in, &port_name, move |message| {
+            let Some((led, level)) = config.led_level(message) else {
+                return;
+            };
+            if let Ok(mut pending) = shared.lock() {
+                pending[led.index() - 1] = Some(level);
+            }
+        })?;
+        Ok(Self {
+            _connection: connection,
+            pending,
+        })
+    }
+
+    /// LED levels received since the last call.
+    pub fn poll(&self) -> Vec<(LedId, u8)> {
+        let Ok(mut pending) = self.pending.lock() else {
+            return Vec::new();
+        };
+        LedId::ALL
+            .into_iter()
+            .zip(mem::replace(&mut *pending, [None; LedId::ALL.len()]))
+            .filter_map(|(led, level)| Some((led, level?)))
+            .collect()
+    }
+}
+
+#[cfg(unix)]
+fn create_virtual(
+    midi_out: MidiOutput,
+    port_name: &str,
+) -> Result<MidiOutputConnection, MidiClockError> {
+    use midir::os::unix::VirtualOutput;
+
+    midi_out
+        .create_virtual(port_name)
+        .map_err(|err| MidiClockError::Connection(err.to_string()))
+}
+
+#[cfg(not(unix))]
+fn create_virtual(
+    _midi_out: MidiOutput,
+    _port_name: &str,
+) -> Result<MidiOutputConnection, MidiClockError> {
+    Err(MidiClockError::Unsupported(
+        "virtual MIDI ports are not available on this platform".into(),
+    ))
+}
+
+#[cfg(unix)]
+fn create_virtual_input(
+    midi_in: MidiInput,
+    port_name: &str,
+    mut on_message: impl FnMut(&[u8]) + Send + 'static,
+) -> Result<MidiInputConnection<()>, MidiClockError> {
+    use midir::os::unix::VirtualInput;
+
+    midi_in
+        .create_virtual(port_name, move |_, message, _| on_message(message), ())
+        .map_err(|err| MidiClockError::Connection(err.to_string()))
+}
+
+#[cfg(not(unix))]
+fn create_virtual_input(
+    _midi_in: MidiInput,
+    _port_name: &str,
+    _on_message: impl FnMut(&[u8]) + Send + 'static,
+) -> Result<MidiInputConnection<()>, MidiClockError> {
+    Err(MidiClockError::Unsupported(
+        "virtual MIDI ports are not available on this platform".into(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x1_controller::Modifiers;
+
+    fn button(id: ButtonId, kind: ButtonEventKind) -> ButtonEvent {
+        ButtonEvent {
+            id,
+            kind,
+            modifiers: Modifiers::default(),
+        }
+    }
+
+    fn pot(id: PotId, normalized: f32) -> PotEvent {
+        PotEvent {
+            id,
+            value: 0,
+            previous: 0,
+            raw: 0,
+            normalized,
+            modifiers: Modifiers::default(),
+        }
+    }
+
+    fn encoder(id: EncoderId, delta: i32) -> EncoderEvent {
+        EncoderEvent {
+            id,
+            value: 0,
+            previous: 0,
+            delta: delta as i8,
+            accelerated_delta: delta,
+            modifiers: Modifiers::default(),
+        }
+    }
+
+    #[test]
+    fn default_numbering_covers_every_control() {
+        let config = SurfaceConfig::default();
+        assert_eq!(
+            config.button_message(&button(ButtonId::Deck1On, ButtonEventKind::Pressed)),
+            [0x90, 36, 127]
+        );
+        assert_eq!(
+            config.button_message(&button(ButtonId::Deck2Sync, ButtonEventKind::Released)),
+            [0x80, 36 + 33, 0]
+        );
+        assert_eq!(
+            config.pot_messages(&pot(PotId::Deck2_3, 1.0)),
+            vec![[0xB0, 23, 127]]
+        );
+        assert_eq!(
+            config.encoder_message(&encoder(EncoderId::Deck2Loop, -2)),
+            Some([0xB0, 83, 62])
+        );
+        assert_eq!(
+            config.encoder_message(&encoder(EncoderId::Deck1Loop, 0)),
+            None
+        );
+    }
+
+    #[test]
+    fn configured_controls_override_defaults() {
+        let config: SurfaceConfig = serde_yaml::from_str(
+            "
+channel: 3
+buttons:
+  - { button: Deck1Play, note: 60, channel: 10 }
+pots:
+  - { pot: Deck1DryWet, cc: 7, high_resolution: true }
+encoders:
+  - { encoder: Deck1Browse, cc: 20 }
+",
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(
+            config.button_message(&button(ButtonId::Deck1Play, ButtonEventKind::Pressed)),
+            [0x99, 60, 127]
+        );
+        assert_eq!(
+            config.pot_messages(&pot(PotId::Deck1DryWet, 0.5)),
+            vec![[0xB2, 7, 64], [0xB2, 39, 0]]
+        );
+        assert_eq!(
+            config.encoder_message(&encoder(EncoderId::Deck1Browse, 100)),
+            Some([0xB2, 20, 127])
+        );
+        assert_eq!(
+            config.pot(PotId::Deck1_1),
+            (
+                Control {
+                    channel: 2,
+                    number: 17
+                },
+                false
+            )
+        );
+    }
+
+    #[test]
+    fn feedback_follows_the_output_table() {
+        let config: SurfaceConfig =
+            serde_yaml::from_str("buttons: [{ button: Deck1Play, note: 60, channel: 10 }]")
+                .unwrap();
+
+        assert_eq!(
+            config.led_level(&[0x99, 60, 127]),
+            Some((LedId::Deck1Play, LED_BRIGHT))
+        );
+        assert_eq!(
+            config.led_level(&[0xB9, 60, 0]),
+            Some((LedId::Deck1Play, LED_DIM))
+        );
+        assert_eq!(
+            config.led_level(&[0x89, 60, 100]),
+            Some((LedId::Deck1Play, LED_DIM))
+        );
+        let (_, level) = config.led_level(&[0x90, 37, 64]).unwrap();
+        assert!(LED_DIM < level && level < LED_BRIGHT, "{level}");
+
+        // Unmapped note, encoder push button without an LED, pitch bend.
+        assert_eq!(config.led_level(&[0x99, 61, 127]), None);
+        let load = config.button(ButtonId::Deck1EncLoad);
+        assert_eq!(config.led_level(&[0x90, load.number, 127]), None);
+        assert_eq!(config.led_level(&[0xE0, 0, 64]), None);
+    }
+
+    #[test]
+    fn rejects_out_of_range_numbers() {
+        let config = |yaml: &str| serde_yaml::from_str::<SurfaceConfig>(yaml).unwrap();
+        assert!(config("channel: 0").validate().is_err());
+        assert!(config("buttons: [{ button: Hotcue, note: 128 }]")
+            .validate()
+            .is_err());
+        assert!(
+            config("pots: [{ pot: Deck1_1, cc: 40, high_resolution: true }]")
+                .validate()
+                .is_err()
+        );
+    }
+}
@@ -1,20 +1,153 @@
-#![allow(dead_code)]
+//! Tap tempo detection.
+
+use serde::Deserialize;
+
+use crate::tempo::{clamp_bpm, TempoRange};
+
+/// Distance of the taps from the fitted beat grid (RMS, as a fraction of the
+/// beat) at which the confidence drops to zero.
+const MAX_JITTER: f64 = 0.1;
+
+/// How the tempo is derived from a series of taps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Estimator {
+    /// Arithmetic mean of the tap intervals.
+    #[default]
+    Mean,
+    /// Median of the tap intervals; a single late or early tap barely moves it.
+    Median,
+    /// Least-squares line through the tap timestamps; spreads the error of a
+    /// single tap over the whole run.
+    Regression,
+}
+
+/// Tap tempo settings (`tap_tempo` in the mapping file).
+///
+/// `outlier_tolerance` drops tap intervals that differ from the median
+/// interval by more than this fraction of it (e.g. `0.2` for ±20%); leave it
+/// out to use every tap. With `rolling_window` every tap after the first
+/// `taps_needed` updates the tempo from the last that many taps, instead of
+/// starting a new cycle.
+///
+/// Taps also set the phase: the last tap lands on a beat of the Link
+/// session, and with `first_tap_downbeat` the first tap of a sequence is the
+/// start of a bar. `force_phase` moves the beat grid of the whole session
+/// even when other peers are connected, instead of keeping theirs.
+///
+/// With `preferred_range`, tapped tempos are halved or doubled into it, so
+/// tapping half time at 64 BPM gives 128 BPM with a range of 80–160.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TapConfig {
+    #[serde(default = "default_taps_needed")]
+    pub taps_needed: usize,
+    #[serde(default = "default_reset_gap")]
+    pub reset_gap: f64,
+    #[serde(default)]
+    pub estimator: Estimator,
+    #[serde(default)]
+    pub outlier_tolerance: Option<f64>,
+    #[serde(default)]
+    pub rolling_window: Option<usize>,
+    #[serde(default)]
+    pub first_tap_downbeat: bool,
+    #[serde(default)]
+    pub force_phase: bool,
+    #[serde(default)]
+    pub preferred_range: Option<TempoRange>,
+}
+
+impl Default for TapConfig {
+    fn default() -> Self {
+        Self {
+            taps_needed: default_taps_needed(),
+            reset_gap: default_reset_gap(),
+            estimator: Estimator::default(),
+            outlier_tolerance: None,
+            rolling_window: None,
+            first_tap_downbeat: false,
+            force_phase: false,
+            preferred_range: None,
+        }
+    }
+}
+
+fn default_taps_needed() -> usize {
+    4
+}
+
+fn default_reset_gap() -> f64 {
+    2.0
+}
+
+/// A tempo estimated from taps.
+///
+/// `confidence` runs from 0.0 to 1.0: it falls with how far the kept taps
+/// are from the fitted beat grid (reaching zero at an RMS of 10% of a beat)
+/// and with the share of taps dropped as outliers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TapEstimate {
+    pub bpm: f64,
+    pub confidence: f64,
+    /// Number of tap intervals the estimate is based on.
+    pub intervals: usize,
+    /// Number of tap intervals rejected as outliers.
+    pub rejected: usize,
+    /// Time of the last tap's beat on the fitted beat grid, in the same
+    /// timebase as the taps. Unlike the raw tap it is not thrown off by the
+    /// jitter of that one tap.
+    pub last_beat: f64,
+    /// Beats from the first tap of the sequence to the last one; a missed
+    /// tap counts the beat it missed.
+    pub beats: usize,
+}
+
+impl TapEstimate {
+    /// The beat the last tap should land on, nearest to `current`, the beat
+    /// the session is at for [`last_beat`](Self::last_beat).
+    ///
+    /// Any whole beat will do, unless the first tap marks the start of a bar
+    /// of `downbeat_quantum` beats; then the last tap's position in the bar
+    /// is fixed too.
+    pub fn aligned_beat(&self, current: f64, downbeat_quantum: Option<f64>) -> f64 {
+        match downbeat_quantum.filter(|&quantum| quantum > 0.0) {
+            Some(quantum) => {
+                let position = (self.beats as f64).rem_euclid(quantum);
+                ((current - position) / quantum).round() * quantum + position
+            }
+            None => current.round(),
+        }
+    }
+}
 
 /// Tap-based tempo estimator.
 ///
 /// Typical usage:
 ///
 /// ```
+/// use x1_tap_clock::tap_tempo::TapTempo;
+///
 /// let mut tapper = TapTempo::new(4, 2.0);
-/// if let Some(bpm) = tapper.add_tap(timestamp_secs) {
-///     println!("Detected tempo: {bpm}");
+/// for timestamp_secs in [0.0, 0.5, 1.0, 1.5] {
+///     if let Some(bpm) = tapper.add_tap(timestamp_secs) {
+///         println!("Detected tempo: {bpm}");
+///     }
 /// }
 /// ```
 #[derive(Debug, Clone)]
 pub struct TapTempo {
     taps_needed: usize,
     reset_gap: f64,
+    estimator: Estimator,
+    outlier_tolerance: Option<f64>,
+    /// Keep estimating over this many taps instead of clearing after each
+    /// estimate.
+    rolling_window: Option<usize>,
+    preferred_range: Option<TempoRange>,
     taps: Vec<f64>,
+    /// Taps of the current sequence that slid out of the rolling window.
+    dropped: usize,
 }
 
 impl TapTempo {
@@ -33,51 +166,226 @@ impl TapTempo {
         Self {
             taps_needed,
             reset_gap,
+            estimator: Estimator::default(),
+            outlier_tolerance: None,
+            rolling_window: None,
+            preferred_range: None,
             taps: Vec::with_capacity(taps_needed),
+            dropped: 0,
         }
     }
 
+    /// Create a tap-tempo helper from mapping settings.
+    pub fn from_config(config: &TapConfig) -> Self {
+        let mut tapper = Self::new(config.taps_needed, config.reset_gap);
+        tapper.set_estimator(config.estimator);
+        tapper.set_outlier_tolerance(config.outlier_tolerance);
+        tapper.set_rolling_window(config.rolling_window);
+        tapper.set_preferred_range(config.preferred_range);
+        tapper
+    }
+
+    /// Choose how the tempo is derived from the taps.
+    pub fn set_estimator(&mut self, estimator: Estimator) {
+        self.estimator = estimator;
+    }
+
+    /// Reject intervals that differ from the median interval by more than
+    /// `tolerance` times it, or use every interval with `None`.
+    pub fn set_outlier_tolerance(&mut self, tolerance: Option<f64>) {
+        self.outlier_tolerance = tolerance;
+    }
+
+    /// Switch to rolling mode: once `taps_needed` taps are in, every further
+    /// tap re-estimates the tempo over the last `window` taps (at least
+    /// `taps_needed`). `None` goes back to clearing the taps after each
+    /// estimate.
+    pub fn set_rolling_window(&mut self, window: Option<usize>) {
+        self.rolling_window = window.map(|window| window.max(self.taps_needed));
+    }
+
+    /// Fold estimated tempos into `range` by factors of two, or report them
+    /// as tapped with `None`. Either way they are clamped to the tempo
+    /// limits.
+    pub fn set_preferred_range(&mut self, range: Option<TempoRange>) {
+        self.preferred_range = range;
+    }
+
     /// Register a tap at the supplied timestamp (seconds).
     ///
     /// Returns `Some(bpm)` when enough taps have been collected to estimate the tempo,
     /// otherwise returns `None`.
     pub fn add_tap(&mut self, timestamp_sec: f64) -> Option<f64> {
+        self.add_tap_estimate(timestamp_sec)
+            .map(|estimate| estimate.bpm)
+    }
+
+    /// Like [`add_tap`](Self::add_tap), but with the confidence of the
+    /// estimate.
+    pub fn add_tap_estimate(&mut self, timestamp_sec: f64) -> Option<TapEstimate> {
         if let Some(&last) = self.taps.last() {
             if timestamp_sec - last > self.reset_gap {
-                self.taps.clear();
+                self.reset();
             }
         }
 
         self.taps.push(timestamp_sec);
+        if let Some(window) = self.rolling_window {
+            let excess = self.taps.len().saturating_sub(window);
+            self.taps.drain(..excess);
+            self.dropped += excess;
+        }
 
         if self.taps.len() < self.taps_needed {
             return None;
         }
 
-        let mut sum = 0.0;
-        for window in self.taps.windows(2) {
-            sum += window[1] - window[0];
+        let estimate = estimate(&self.taps, self.estimator, self.outlier_tolerance).map(|e| {
+            // Folding changes how many beats the taps span, not where the
+            // last one is.
+            let factor = self
+                .preferred_range
+                .map_or(1.0, |range| range.fold_factor(e.bpm));
+            let beats = (e.beats + self.dropped) as f64 * factor;
+            TapEstimate {
+                bpm: clamp_bpm(e.bpm * factor),
+                beats: beats.round() as usize,
+                ..e
+            }
+        });
+        if self.rolling_window.is_none() {
+            self.reset();
         }
+        estimate
+    }
 
-        let avg_interval = sum / (self.taps.len() - 1) as f64;
+    /// Reset the tap history explicitly.
+    pub fn reset(&mut self) {
         self.taps.clear();
+        self.dropped = 0;
+    }
+}
+
+/// Estimate the tempo of `taps` (ascending timestamps in seconds).
+fn estimate(taps: &[f64], estimator: Estimator, tolerance: Option<f64>) -> Option<TapEstimate> {
+    let intervals: Vec<f64> = taps.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    let reference = median(&intervals)?;
+    if reference <= 0.0 {
+        return None;
+    }
 
-        if avg_interval > 0.0 {
-            Some(60.0 / avg_interval)
-        } else {
-            None
+    // An interval is kept if it is close enough to the median; a tap is kept
+    // if at least one of its intervals is.
+    let kept: Vec<bool> = intervals
+        .iter()
+        .map(|&interval| match tolerance {
+            Some(tolerance) => (interval - reference).abs() <= tolerance * reference,
+            None => true,
+        })
+        .collect();
+    let accepted: Vec<f64> = intervals
+        .iter()
+        .zip(&kept)
+        .filter(|(_, &keep)| keep)
+        .map(|(&interval, _)| interval)
+        .collect();
+    let rejected = intervals.len() - accepted.len();
+    // Number the taps by the beats they fall on rather than by position, so
+    // a missed (or doubled) tap skips (or repeats) a beat instead of bending
+    // the fit. Counting from the last kept tap keeps an outlier from
+    // shifting the taps after it.
+    let mut points: Vec<(f64, f64)> = Vec::with_capacity(taps.len());
+    let mut anchor = (0.0, taps[0]);
+    let mut beats = 0.0;
+    for (index, &time) in taps.iter().enumerate() {
+        beats = anchor.0 + ((time - anchor.1) / reference).round();
+        let before = index > 0 && kept[index - 1];
+        let after = kept.get(index) == Some(&true);
+        if before || after {
+            anchor = (beats, time);
+            points.push(anchor);
         }
     }
 
-    /// Reset the tap history explicitly.
-    pub fn reset(&mut self) {
-        self.taps.clear();
+    let interval = match estimator {
+        Estimator::Mean => mean(&accepted)?,
+        Estimator::Median => median(&accepted)?,
+        Estimator::Regression => slope(&points)?,
+    };
+    if interval <= 0.0 {
+        return None;
     }
+
+    // Beat grid with this interval that best fits the kept taps.
+    let offset = points
+        .iter()
+        .map(|&(beat, time)| time - beat * interval)
+        .sum::<f64>()
+        / points.len() as f64;
+
+    let residual = points
+        .iter()
+        .map(|&(beat, time)| (time - offset - beat * interval).powi(2))
+        .sum::<f64>()
+        / points.len() as f64;
+    let jitter = residual.sqrt() / interval;
+    let stability = (1.0 - jitter / MAX_JITTER).clamp(0.0, 1.0);
+    let confidence = stability * points.len() as f64 / taps.len() as f64;
+
+    Some(TapEstimate {
+        bpm: 60.0 / interval,
+        confidence,
+        intervals: accepted.len(),
+        rejected,
+        last_beat: offset + beats * interval,
+        beats: beats as usize,
+    })
+}
+
+fn mean(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    Some(values.iter().sum::<f64>() / values.len() as f64)
+}
+
+fn median(values: &[f64]) -> Option<f64> {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let middle = sorted.len() / 2;
+    match sorted.len() {
+        0 => None,
+        len if len % 2 == 1 => Some(sorted[middle]),
+        _ => Some((sorted[middle - 1] + sorted[middle]) / 2.0),
+    }
+}
+
+/// Least-squares slope of `points` (x, y).
+fn slope(points: &[(f64, f64)]) -> Option<f64> {
+    if points.len() < 2 {
+        return None;
+    }
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let (covariance, variance) = points.iter().fold((0.0, 0.0), |(cov, var), &(x, y)| {
+        (
+            cov + (x - mean_x) * (y - mean_y),
+            var + (x - mean_x).powi(2),
+        )
+    });
+    (variance > 0.0).then(|| covariance / variance)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::TapTempo;
+    use super::*;
+
+    /// Feed `taps` and return the last estimate.
+    fn run(tapper: &mut TapTempo, taps: &[f64]) -> Option<TapEstimate> {
+        taps.iter()
+            .fold(None, |_, &tap| tapper.add_tap_estimate(tap))
+    }
 
     #[test]
     fn computes_expected_bpm() {
@@ -104,4 +412,160 @@ mod tests {
         assert!(tapper.add_tap(2.2).is_none());
         assert!(tapper.add_tap(2.4).is_some());
     }
+
+    #[test]
+    fn rolling_mode_refines_on_every_tap() {
+        let mut tapper = TapTempo::new(3, 1.0);
+        tapper.set_rolling_window(Some(5));
+
+        assert!(tapper.add_tap(0.0).is_none());
+        assert!(tapper.add_tap(0.5).is_none());
+        assert!((tapper.add_tap(1.1).unwrap() - 60.0 / 0.55).abs() < 1e-6);
+        // Every further tap updates the tempo over the last five taps.
+        assert!((tapper.add_tap(1.6).unwrap() - 60.0 / (1.6 / 3.0)).abs() < 1e-6);
+        assert!(tapper.add_tap(2.1).is_some());
+        let estimate = tapper.add_tap_estimate(2.6).unwrap();
+        assert!(
+            (estimate.bpm - 60.0 / (2.1 / 4.0)).abs() < 1e-6,
+            "{estimate:?}"
+        );
+        assert_eq!(estimate.beats, 5);
+
+        // A pause still starts over.
+        assert!(tapper.add_tap(4.0).is_none());
+        assert!(tapper.add_tap(4.5).is_none());
+        assert!(tapper.add_tap(5.0).is_some());
+    }
+
+    #[test]
+    fn robust_estimators_shrug_off_a_late_tap() {
+        // 120 BPM with the fourth tap 120 ms late.
+        let taps = [0.0, 0.5, 1.0, 1.62, 2.0, 2.5, 3.0, 3.5];
+
+        let mut mean = TapTempo::new(taps.len(), 2.0);
+        let unfiltered = run(&mut mean, &taps).unwrap();
+        assert!(unfiltered.confidence < 0.5, "{unfiltered:?}");
+
+        for estimator in [Estimator::Median, Estimator::Regression, Estimator::Mean] {
+            let mut tapper = TapTempo::new(taps.len(), 2.0);
+            tapper.set_estimator(estimator);
+            tapper.set_outlier_tolerance(Some(0.15));
+            let estimate = run(&mut tapper, &taps).unwrap();
+            assert!(
+                (estimate.bpm - 120.0).abs() < 0.1,
+                "{estimator:?}: {estimate:?}"
+            );
+            assert_eq!(estimate.rejected, 2, "{estimator:?}");
+            assert!(estimate.confidence > 0.6, "{estimator:?}: {estimate:?}");
+        }
+    }
+
+    #[test]
+    fn a_missed_tap_skips_a_beat() {
+        // 120 BPM with the tap on 1.5 s missed.
+        let taps = [0.0, 0.5, 1.0, 2.0, 2.5, 3.0];
+        for estimator in [Estimator::Mean, Estimator::Median, Estimator::Regression] {
+            let mut tapper = TapTempo::new(taps.len(), 2.0);
+            tapper.set_estimator(estimator);
+            tapper.set_outlier_tolerance(Some(0.15));
+            let estimate = run(&mut tapper, &taps).unwrap();
+            assert!(
+                (estimate.bpm - 120.0).abs() < 1e-6,
+                "{estimator:?}: {estimate:?}"
+            );
+            assert_eq!(estimate.rejected, 1);
+            assert_eq!(estimate.beats, 6);
+            assert!((estimate.last_beat - 3.0).abs() < 1e-6);
+            assert!((estimate.confidence - 1.0).abs() < 1e-6);
+        }
+
+        // A stray tap halfway between beats is dropped without shifting the
+        // beats of the taps after it.
+        let mut tapper = TapTempo::new(6, 2.0);
+        tapper.set_estimator(Estimator::Regression);
+        tapper.set_outlier_tolerance(Some(0.15));
+        let estimate = run(&mut tapper, &[0.0, 0.5, 1.0, 1.75, 2.0, 2.5]).unwrap();
+        assert!((estimate.bpm - 120.0).abs() < 1e-6, "{estimate:?}");
+        assert_eq!(estimate.beats, 5);
+        assert!((estimate.confidence - 5.0 / 6.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn outlying_first_and_last_taps_are_dropped() {
+        let mut tapper = TapTempo::new(6, 2.0);
+        tapper.set_estimator(Estimator::Regression);
+        tapper.set_outlier_tolerance(Some(0.15));
+        let estimate = run(&mut tapper, &[0.0, 0.8, 1.3, 1.8, 2.3, 2.6]).unwrap();
+        assert!((estimate.bpm - 120.0).abs() < 1e-6, "{estimate:?}");
+        assert_eq!(estimate.rejected, 2);
+        assert!((estimate.confidence - 4.0 / 6.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn steady_taps_are_confident() {
+        let mut tapper = TapTempo::new(6, 2.0);
+        tapper.set_estimator(Estimator::Regression);
+        let estimate = run(&mut tapper, &[0.0, 0.4, 0.8, 1.2, 1.6, 2.0]).unwrap();
+        assert!((estimate.bpm - 150.0).abs() < 1e-6);
+        assert!((estimate.confidence - 1.0).abs() < 1e-6);
+        assert_eq!(estimate.intervals, 5);
+    }
+
+    #[test]
+    fn folds_half_time_taps_into_the_preferred_range() {
+        let mut tapper = TapTempo::new(4, 2.0);
+        tapper.set_preferred_range(Some(TempoRange::new(80.0, 160.0)));
+        let estimate = run(&mut tapper, &[0.0, 0.9375, 1.875, 2.8125]).unwrap();
+        assert!((estimate.bpm - 128.0).abs() < 1e-6, "{estimate:?}");
+        assert_eq!(estimate.beats, 6);
+
+        let mut unfolded = TapTempo::new(2, 20.0);
+        assert_eq!(unfolded.add_tap(0.0), None);
+        assert_eq!(unfolded.add_tap(10.0), Some(crate::tempo::MIN_BPM));
+    }
+
+    #[test]
+    fn aligns_to_the_nearest_matching_beat() {
+        let estimate = TapEstimate {
+            bpm: 120.0,
+            confidence: 1.0,
+            intervals: 5,
+            rejected: 0,
+            last_beat: 0.0,
+            beats: 5,
+        };
+        assert_eq!(estimate.aligned_beat(17.4, None), 17.0);
+        assert_eq!(estimate.aligned_beat(17.6, None), 18.0);
+        // Six taps from a downbeat end on beat 2 of a 4-beat bar (1, 5, 9...).
+        assert_eq!(estimate.aligned_beat(17.6, Some(4.0)), 17.0);
+        assert_eq!(estimate.aligned_beat(19.4, Some(4.0)), 21.0);
+        assert_eq!(estimate.aligned_beat(-0.4, Some(4.0)), 1.0);
+    }
+
+    #[test]
+    fn phase_comes_from_the_fitted_grid() {
+        // 120 BPM from 10 s, with a few ms of jitter, the fourth tap 120 ms
+        // late and the last one 15 ms early.
+        let jitter = [0.008, -0.006, 0.01, 0.12, -0.004, 0.007, -0.009, -0.015];
+        let taps: Vec<f64> = jitter
+            .iter()
+            .enumerate()
+            .map(|(beat, offset)| 10.0 + 0.5 * beat as f64 + offset)
+            .collect();
+        for (estimator, error) in [
+            (Estimator::Mean, 0.01),
+            (Estimator::Median, 0.025),
+            (Estimator::Regression, 0.01),
+        ] {
+            let mut tapper = TapTempo::new(taps.len(), 2.0);
+            tapper.set_estimator(estimator);
+            tapper.set_outlier_tolerance(Some(0.15));
+            let estimate = run(&mut tapper, &taps).unwrap();
+            assert_eq!(estimate.beats, 7);
+            assert!(
+                (estimate.last_beat - 13.5).abs() < error,
+                "{estimator:?}: {estimate:?}"
+            );
+        }
+    }
 }
@@ -0,0 +1,94 @@
+//! Tempo limits shared by Link, the MIDI clock and tap tempo, and the
+//! preferred-range policy for tapped tempos.
+
+use serde::Deserialize;
+
+/// Slowest tempo the app sets.
+pub const MIN_BPM: f64 = 30.0;
+/// Fastest tempo the app sets.
+pub const MAX_BPM: f64 = 300.0;
+
+/// Clamp `bpm` to [`MIN_BPM`]..=[`MAX_BPM`].
+pub fn clamp_bpm(bpm: f64) -> f64 {
+    if bpm.is_nan() {
+        return MIN_BPM;
+    }
+    bpm.clamp(MIN_BPM, MAX_BPM)
+}
+
+/// A preferred tempo range, e.g. 80–160 BPM.
+///
+/// Tempos outside it are halved or doubled until they fit, so tapping along
+/// in half or double time still gives the intended tempo. A range narrower
+/// than an octave may contain no such multiple; the nearest one is used then.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TempoRange {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl TempoRange {
+    pub fn new(min: f64, max: f64) -> Self {
+        Self { min, max }
+    }
+
+    /// Whether the range is usable: positive, increasing and within the
+    /// tempo limits.
+    pub fn is_valid(&self) -> bool {
+        MIN_BPM <= self.min && self.min < self.max && self.max <= MAX_BPM
+    }
+
+    /// `bpm` multiplied by the power of two that brings it into the range.
+    pub fn fold(&self, bpm: f64) -> f64 {
+        bpm * self.fold_factor(bpm)
+    }
+
+    /// The power of two (…, 0.5, 1, 2, …) that [`fold`](Self::fold) applies.
+    pub fn fold_factor(&self, bpm: f64) -> f64 {
+        if !(bpm.is_finite() && bpm > 0.0 && self.is_valid()) {
+            return 1.0;
+        }
+        let mut factor = 1.0;
+        while bpm * factor < self.min {
+            factor *= 2.0;
+        }
+        while bpm * factor > self.max {
+            factor /= 2.0;
+        }
+        // Only possible for ranges narrower than an octave: `bpm * factor`
+        // is now below `min` and `bpm * factor * 2` above `max`.
+        if bpm * factor < self.min && bpm * factor * 2.0 - self.max < self.min - bpm * factor {
+            factor *= 2.0;
+        }
+        factor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folds_by_octaves_into_the_range() {
+        let range = TempoRange::new(80.0, 160.0);
+        assert_eq!(range.fold(64.0), 128.0);
+        assert_eq!(range.fold(35.0), 140.0);
+        assert_eq!(range.fold(250.0), 125.0);
+        assert_eq!(range.fold(120.0), 120.0);
+        assert_eq!(range.fold(160.0), 160.0);
+        assert_eq!(range.fold_factor(40.0), 2.0);
+
+        let narrow = TempoRange::new(100.0, 120.0);
+        assert_eq!(narrow.fold(70.0), 140.0);
+        assert_eq!(narrow.fold(90.0), 90.0);
+    }
+
+    #[test]
+    fn clamps_to_the_shared_limits() {
+        assert_eq!(clamp_bpm(10.0), MIN_BPM);
+        assert_eq!(clamp_bpm(999.0), MAX_BPM);
+        assert_eq!(clamp_bpm(f64::NAN), MIN_BPM);
+        assert!(!TempoRange::new(20.0, 160.0).is_valid());
+    }
+}
@@ -0,0 +1,92 @@
+//! Linear beat timelines shared between the Link session and the MIDI clock.
+
+use std::time::{Duration, Instant};
+
+/// Constant-tempo mapping between beats and [`Instant`]s.
+///
+/// A timeline is anchored at `at`, where it is at `beat`, and advances at
+/// `tempo` beats per minute in both directions from there. It is a plain
+/// `Copy` value, so a snapshot of the Link session can be handed to other
+/// threads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeline {
+    pub tempo: f64,
+    pub beat: f64,
+    pub at: Instant,
+}
+
+impl Timeline {
+    pub fn new(tempo: f64, beat: f64, at: Instant) -> Self {
+        Self { tempo, beat, at }
+    }
+
+    /// Beat position at `time`.
+    pub fn beat_at(&self, time: Instant) -> f64 {
+        let seconds = if time >= self.at {
+            (time - self.at).as_secs_f64()
+        } else {
+            -(self.at - time).as_secs_f64()
+        };
+        self.beat + seconds * self.tempo / 60.0
+    }
+
+    /// Point in time at which the timeline reaches `beat`.
+    pub fn time_at_beat(&self, beat: f64) -> Instant {
+        let seconds = (beat - self.beat) * 60.0 / self.tempo.max(f64::MIN_POSITIVE);
+        if !seconds.is_finite() {
+            return self.at;
+        }
+        if seconds >= 0.0 {
+            self.at
+                .checked_add(Duration::from_secs_f64(seconds))
+                .unwrap_or(self.at)
+        } else {
+            self.at
+                .checked_sub(Duration::from_secs_f64(-seconds))
+                .unwrap_or(self.at)
+        }
+    }
+
+    /// Same timeline with a new tempo from `at` on, keeping the beat
+    /// position at `at` continuous.
+    pub fn with_tempo(&self, tempo: f64, at: Instant) -> Self {
+        Self::new(tempo, self.beat_at(at), at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_beats_both_ways() {
+        let origin = Instant::now() + Duration::from_secs(10);
+        let timeline = Timeline::new(120.0, 4.0, origin);
+
+        assert_eq!(timeline.beat_at(origin), 4.0);
+        assert!((timeline.beat_at(origin + Duration::from_secs(1)) - 6.0).abs() < 1e-9);
+        assert!((timeline.beat_at(origin - Duration::from_secs(1)) - 2.0).abs() < 1e-9);
+        assert_eq!(
+            timeline.time_at_beat(5.0),
+            origin + Duration::from_millis(500)
+        );
+        assert_eq!(
+            timeline.time_at_beat(3.0),
+            origin - Duration::from_millis(500)
+        );
+    }
+
+    #[test]
+    fn tempo_change_keeps_the_phase() {
+        let origin = Instant::now();
+        let timeline = Timeline::new(120.0, 0.0, origin);
+        let change = origin + Duration::from_millis(750);
+
+        let faster = timeline.with_tempo(240.0, change);
+        assert!((faster.beat_at(change) - 1.5).abs() < 1e-9);
+        assert_eq!(
+            faster.time_at_beat(2.0),
+            change + Duration::from_millis(125)
+        );
+    }
+}
@@ -0,0 +1,331 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rusb::Error;
+
+use super::transport::{X1Transport, LED_FRAME_LEN, REPORT_LEN};
+
+const CAPTURE_HEADER: &str = "# x1-tap-clock capture v1";
+
+/// Direction of a captured USB transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// A 24-byte input report read from the device.
+    In,
+    /// A 32-byte LED frame written to the device.
+    Out,
+}
+
+/// A single timestamped transfer in a capture file.
+///
+/// Captures are plain text, one record per line:
+/// `<microseconds since start> <in|out> <hex bytes>`. Lines starting with `#`
+/// are comments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureRecord {
+    pub at: Duration,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+impl fmt::Display for CaptureRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.direction {
+            Direction::In => "in",
+            Direction::Out => "out",
+        };
+        write!(f, "{} {} ", self.at.as_micros(), direction)?;
+        for byte in &self.data {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl CaptureRecord {
+    /// Parse one capture line; returns `Ok(None)` for blank and comment lines.
+    pub fn parse(line: &str) -> Result<Option<Self>, String> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+
+        let mut fields = line.split_whitespace();
+        let (Some(at), Some(direction), Some(hex), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(format!("expected 3 fields in \"{line}\""));
+        };
+
+        let at = at
+            .parse::<u64>()
+            .map(Duration::from_micros)
+            .map_err(|err| format!("bad timestamp \"{at}\": {err}"))?;
+        let (direction, expected_len) = match direction {
+            "in" => (Direction::In, REPORT_LEN),
+            "out" => (Direction::Out, LED_FRAME_LEN),
+            other => return Err(format!("unknown direction \"{other}\"")),
+        };
+        if hex.len() != expected_len * 2 {
+            return Err(format!(
+                "expected {expected_len} bytes for \"{direction:?}\", got \"{hex}\""
+            ));
+        }
+        // Checked first so that slicing below stays on character boundaries.
+        if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(format!("bad hex \"{hex}\""));
+        }
+        let data = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("bad hex \"{hex}\": {err}"))?;
+
+        Ok(Some(Self {
+            at,
+            direction,
+            data,
+        }))
+    }
+}
+
+/// Read every record from a capture file.
+pub fn read_capture(path: impl AsRef<Path>) -> io::Result<Vec<CaptureRecord>> {
+    parse_capture(BufReader::new(File::open(path)?))
+}
+
+/// Parse capture records from any buffered reader.
+pub fn parse_capture(reader: impl BufRead) -> io::Result<Vec<CaptureRecord>> {
+    let mut records = Vec::new();
+    for (number, line) in reader.lines().enumerate() {
+        match CaptureRecord::parse(&line?) {
+            Ok(Some(record)) => records.push(record),
+            Ok(None) => {}
+            Err(err) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("capture line {}: {}", number + 1, err),
+                ))
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Shared, timestamped sink for capture records.
+///
+/// Clones append to the same file, so a single capture survives reconnects.
+#[derive(Clone)]
+pub struct CaptureWriter {
+    inner: Arc<Mutex<CaptureSink>>,
+}
+
+struct CaptureSink {
+    out: BufWriter<File>,
+    started: Instant,
+}
+
+impl CaptureWriter {
+    /// Create (or truncate) a capture file.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "{}", CAPTURE_HEADER)?;
+        out.flush()?;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(CaptureSink {
+                out,
+                started: Instant::now(),
+            })),
+        })
+    }
+
+    fn record(&self, direction: Direction, data: &[u8]) {
+        let mut sink = match self.inner.lock() {
+            Ok(sink) => sink,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let record = CaptureRecord {
+            at: sink.started.elapsed(),
+            direction,
+            data: data.to_vec(),
+        };
+        // Flush per record so a capture is usable even if the app is killed.
+        if let Err(err) = writeln!(sink.out, "{}", record).and_then(|_| sink.out.flush()) {
+            eprintln!("capture write error: {}", err);
+        }
+    }
+}
+
+/// Transport wrapper that logs every report and LED frame to a capture file.
+pub struct CaptureTransport<T> {
+    inner: T,
+    writer: CaptureWriter,
+}
+
+impl<T: X1Transport> CaptureTransport<T> {
+    pub fn new(inner: T, writer: CaptureWriter) -> Self {
+        Self { inner, writer }
+    }
+}
+
+impl<T: X1Transport> X1Transport for CaptureTransport<T> {
+    fn read_report(
+        &mut self,
+        buf: &mut [u8; REPORT_LEN],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        let len = self.inner.read_report(buf, timeout)?;
+        if len == REPORT_LEN {
+            self.writer.record(Direction::In, buf);
+        }
+        Ok(len)
+    }
+
+    fn write_leds(&mut self, frame: &[u8; LED_FRAME_LEN], timeout: Duration) -> rusb::Result<()> {
+        self.inner.write_leds(frame, timeout)?;
+        self.writer.record(Direction::Out, frame);
+        Ok(())
+    }
+
+    fn read_unlock_ack(&mut self, timeout: Duration) -> rusb::Result<()> {
+        self.inner.read_unlock_ack(timeout)
+    }
+}
+
+/// Transport that plays the input reports of a capture back to the controller.
+///
+/// LED frames written by the controller are collected so they can be
+/// compared against the `out` records of the capture.
+pub struct ReplayTransport {
+    reports: VecDeque<CaptureRecord>,
+    paced: bool,
+    started: Option<Instant>,
+    led_frames: Vec<[u8; LED_FRAME_LEN]>,
+}
+
+impl ReplayTransport {
+    /// Replay `records`; when `paced` is set, reports are released at their
+    /// recorded offsets instead of as fast as the controller polls.
+    pub fn new(records: Vec<CaptureRecord>, paced: bool) -> Self {
+        Self {
+            reports: records
+                .into_iter()
+                .filter(|record| record.direction == Direction::In)
+                .collect(),
+            paced,
+            started: None,
+            led_frames: Vec::new(),
+        }
+    }
+
+    /// Whether every input report has been delivered.
+    pub fn is_finished(&self) -> bool {
+        self.reports.is_empty()
+    }
+
+    /// LED frames the controller wrote during the replay.
+    pub fn led_frames(&self) -> &[[u8; LED_FRAME_LEN]] {
+        &self.led_frames
+    }
+}
+
+impl X1Transport for ReplayTransport {
+    fn read_report(
+        &mut self,
+        buf: &mut [u8; REPORT_LEN],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        let Some(next) = self.reports.front() else {
+            return Err(Error::Timeout);
+        };
+
+        if self.paced {
+            let started = *self.started.get_or_insert_with(Instant::now);
+            let wait = (started + next.at).saturating_duration_since(Instant::now());
+            if wait > timeout {
+                thread::sleep(timeout);
+                return Err(Error::Timeout);
+            }
+            thread::sleep(wait);
+        }
+
+        let record = self.reports.pop_front().ok_or(Error::Timeout)?;
+        buf.copy_from_slice(&record.data);
+        Ok(REPORT_LEN)
+    }
+
+    fn write_leds(&mut self, frame: &[u8; LED_FRAME_LEN], _timeout: Duration) -> rusb::Result<()> {
+        self.led_frames.push(*frame);
+        Ok(())
+    }
+
+    fn read_unlock_ack(&mut self, _timeout: Duration) -> rusb::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::x1_controller::{ButtonEventKind, ButtonId, X1Controller};
+
+    #[test]
+    fn record_round_trips_through_text() {
+        let record = CaptureRecord {
+            at: Duration::from_micros(1500),
+            direction: Direction::In,
+            data: (0..REPORT_LEN as u8).collect(),
+        };
+        let parsed = CaptureRecord::parse(&record.to_string()).unwrap();
+        assert_eq!(parsed, Some(record));
+        assert_eq!(CaptureRecord::parse("# comment").unwrap(), None);
+        assert!(CaptureRecord::parse("10 in 00").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        let short = format!("0 out {}", "00".repeat(LED_FRAME_LEN - 1));
+        assert!(CaptureRecord::parse(&short).is_err());
+        // Right byte length, but not hex: must not panic on a char boundary.
+        let accented = format!("0 out {}", "é".repeat(LED_FRAME_LEN));
+        assert!(CaptureRecord::parse(&accented).is_err());
+    }
+
+    #[test]
+    fn replays_fixture_through_callbacks() {
+        let fixture = include_str!("../../fixtures/shift_sync_taps.x1cap");
+        let records = parse_capture(fixture.as_bytes()).unwrap();
+        let mut controller = X1Controller::with_transport(ReplayTransport::new(records, false));
+        let presses = Arc::new(Mutex::new(Vec::new()));
+        let sink = presses.clone();
+        controller.set_button_callback(move |_, event, _, _| {
+            if event.kind == ButtonEventKind::Pressed {
+                sink.lock().unwrap().push((event.id, event.modifiers.shift));
+            }
+        });
+
+        while !controller.transport().unwrap().is_finished() {
+            controller.poll_once().unwrap();
+        }
+
+        assert_eq!(
+            *presses.lock().unwrap(),
+            vec![
+                (ButtonId::Shift, true),
+                (ButtonId::Deck1Sync, true),
+                (ButtonId::Deck1Sync, true),
+                (ButtonId::Deck1Play, false),
+            ]
+        );
+    }
+}
@@ -0,0 +1,401 @@
+use std::{
+    collections::HashMap,
+    f64::consts::TAU,
+    time::{Duration, Instant},
+};
+
+use super::led::{LedId, LED_REPORT_ID};
+use super::transport::LED_FRAME_LEN;
+use super::x1_controller::LED_DIM;
+
+/// Compositing layers, lowest priority first.
+///
+/// For every LED the highest layer with a visible animation wins; effects can
+/// be transparent for part of their cycle, letting lower layers show through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Layer {
+    /// Steady state, e.g. a mode or toggle indicator.
+    Base,
+    /// Beat-synced animations.
+    Beat,
+    /// Short transient feedback such as a tap flash.
+    Flash,
+    /// Overrides everything, e.g. a count-in or warning.
+    Overlay,
+}
+
+impl Layer {
+    const ALL: [Layer; 4] = [Layer::Base, Layer::Beat, Layer::Flash, Layer::Overlay];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// What an animation's time axis is measured in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timebase {
+    /// Seconds since the animation was set.
+    Wall,
+    /// Beats on the session timeline passed to [`LedCompositor::render`].
+    /// Periodic effects line up with the beat grid; the animation is hidden
+    /// while no beat position is available.
+    Beats,
+}
+
+/// Brightness over time. Periods and durations are in seconds or beats
+/// depending on the [`Timebase`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Effect {
+    /// Constant brightness.
+    Solid(u8),
+    /// `on` for the first `duty` fraction of every period, `off` for the rest
+    /// (`None` is transparent).
+    Blink {
+        on: u8,
+        off: Option<u8>,
+        period: f64,
+        duty: f64,
+    },
+    /// Smooth sine pulse between `low` and `high`.
+    Pulse { low: u8, high: u8, period: f64 },
+    /// Linear fade from `from` to `to`, then hold `to`.
+    Fade { from: u8, to: u8, duration: f64 },
+    /// One step of a chase across `count` LEDs: lit while the chase is at
+    /// `position`. Use [`LedCompositor::chase`] to set up a whole row.
+    Chase {
+        on: u8,
+        off: Option<u8>,
+        period: f64,
+        position: usize,
+        count: usize,
+    },
+    /// Beat indicator: `accent` at the start of each `quantum`-beat bar,
+    /// `beat` at the start of every other beat, transparent otherwise.
+    Metronome {
+        accent: u8,
+        beat: u8,
+        quantum: f64,
+        accent_width: f64,
+        beat_width: f64,
+    },
+}
+
+impl Effect {
+    /// Brightness at `position` on the timeline (absolute beat or seconds
+    /// since start) and `elapsed` since the animation began.
+    fn level(&self, position: f64, elapsed: f64) -> Option<u8> {
+        match *self {
+            Effect::Solid(level) => Some(level),
+            Effect::Blink {
+                on,
+                off,
+                period,
+                duty,
+            } => {
+                if cycle(position, period) < duty {
+                    Some(on)
+                } else {
+                    off
+                }
+            }
+            Effect::Pulse { low, high, period } => {
+                let t = (1.0 - (cycle(position, period) * TAU).cos()) / 2.0;
+                Some(lerp(low, high, t))
+            }
+            Effect::Fade { from, to, duration } => {
+                if duration <= 0.0 {
+                    return Some(to);
+                }
+                Some(lerp(from, to, (elapsed / duration).clamp(0.0, 1.0)))
+            }
+            Effect::Chase {
+                on,
+                off,
+                period,
+                position: index,
+                count,
+            } => {
+                let step = (cycle(position, period) * count.max(1) as f64) as usize;
+                if step == index {
+                    Some(on)
+                } else {
+                    off
+                }
+            }
+            Effect::Metronome {
+                accent,
+                beat,
+                quantum,
+                accent_width,
+                beat_width,
+            } => {
+                if position.rem_euclid(quantum) < accent_width {
+                    Some(accent)
+                } else if position.rem_euclid(1.0) < beat_width {
+                    Some(beat)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+fn cycle(position: f64, period: f64) -> f64 {
+    if period <= 0.0 {
+        return 0.0;
+    }
+    (position / period).rem_euclid(1.0)
+}
+
+fn lerp(from: u8, to: u8, t: f64) -> u8 {
+    (f64::from(from) + (f64::from(to) - f64::from(from)) * t).round() as u8
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Animation {
+    effect: Effect,
+    timebase: Timebase,
+    started: Instant,
+    start_beat: Option<f64>,
+    until: Option<Instant>,
+}
+
+/// Layered LED state that flattens into the frame sent to the X1.
+///
+/// Feedback sources write into their own [`Layer`] instead of fighting over
+/// the raw LED buffer; [`render`](Self::render) resolves them once per frame.
+#[derive(Debug, Clone)]
+pub struct LedCompositor {
+    layers: [HashMap<LedId, Animation>; 4],
+    background: u8,
+}
+
+impl Default for LedCompositor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LedCompositor {
+    pub fn new() -> Self {
+        Self {
+            layers: Default::default(),
+            background: LED_DIM,
+        }
+    }
+
+    /// Brightness of LEDs no layer covers.
+    pub fn set_background(&mut self, level: u8) {
+        self.background = level;
+    }
+
+    /// Run `effect` on `led` in `layer` until replaced or cleared.
+    pub fn set(&mut self, layer: Layer, led: LedId, effect: Effect, timebase: Timebase) {
+        self.insert(layer, led, effect, timebase, None);
+    }
+
+    /// Run `effect` on `led` in `layer` for a limited wall-clock time.
+    pub fn set_for(
+        &mut self,
+        layer: Layer,
+        led: LedId,
+        effect: Effect,
+        timebase: Timebase,
+        duration: Duration,
+    ) {
+        self.insert(
+            layer,
+            led,
+            effect,
+            timebase,
+            Some(Instant::now() + duration),
+        );
+    }
+
+    /// Light `led` at `level` on the flash layer for `duration`.
+    pub fn flash(&mut self, led: LedId, level: u8, duration: Duration) {
+        self.set_for(
+            Layer::Flash,
+            led,
+            Effect::Solid(level),
+            Timebase::Wall,
+            duration,
+        );
+    }
+
+    /// Chase a single lit LED along `leds`, completing a pass every `period`.
+    pub fn chase(
+        &mut self,
+        layer: Layer,
+        leds: &[LedId],
+        on: u8,
+        off: Option<u8>,
+        period: f64,
+        timebase: Timebase,
+    ) {
+        for (position, &led) in leds.iter().enumerate() {
+            let effect = Effect::Chase {
+                on,
+                off,
+                period,
+                position,
+                count: leds.len(),
+            };
+            self.set(layer, led, effect, timebase);
+        }
+    }
+
+    pub fn clear(&mut self, layer: Layer, led: LedId) {
+        self.layers[layer.index()].remove(&led);
+    }
+
+    pub fn clear_layer(&mut self, layer: Layer) {
+        self.layers[layer.index()].clear();
+    }
+
+    pub fn is_active(&self, layer: Layer, led: LedId) -> bool {
+        self.layers[layer.index()].contains_key(&led)
+    }
+
+    /// Flatten all layers into an LED frame. `beat` is the current position on
+    /// the beat timeline, if any; expired animations are dropped.
+    pub fn render(&mut self, now: Instant, beat: Option<f64>) -> [u8; LED_FRAME_LEN] {
+        let mut frame = [self.background; LED_FRAME_LEN];
+        frame[0] = LED_REPORT_ID;
+
+        for layer in self.layers.iter_mut() {
+            layer.retain(|_, anim| !matches!(anim.until, Some(until) if now >= until));
+        }
+
+        for led in LedId::ALL {
+            for layer in Layer::ALL.iter().rev() {
+                let Some(anim) = self.layers[layer.index()].get_mut(&led) else {
+                    continue;
+                };
+                let timeline = match anim.timebase {
+                    Timebase::Wall => {
+                        let elapsed = now.saturating_duration_since(anim.started).as_secs_f64();
+                        Some((elapsed, elapsed))
+                    }
+                    Timebase::Beats => beat.map(|beat| {
+                        let start = *anim.start_beat.get_or_insert(beat);
+                        (beat, beat - start)
+                    }),
+                };
+                let level =
+                    timeline.and_then(|(position, elapsed)| anim.effect.level(position, elapsed));
+                if let Some(level) = level {
+                    frame[led.index()] = level;
+                    break;
+                }
+            }
+        }
+        frame
+    }
+
+    fn insert(
+        &mut self,
+        layer: Layer,
+        led: LedId,
+        effect: Effect,
+        timebase: Timebase,
+        until: Option<Instant>,
+    ) {
+        self.layers[layer.index()].insert(
+            led,
+            Animation {
+                effect,
+                timebase,
+                started: Instant::now(),
+                start_beat: None,
+                until,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LED: LedId = LedId::Deck1Sync;
+
+    #[test]
+    fn higher_layers_win_and_transparency_falls_through() {
+        let mut leds = LedCompositor::new();
+        let now = Instant::now();
+        leds.set(Layer::Base, LED, Effect::Solid(0x10), Timebase::Wall);
+        leds.set(
+            Layer::Beat,
+            LED,
+            Effect::Blink {
+                on: 0x40,
+                off: None,
+                period: 1.0,
+                duty: 0.25,
+            },
+            Timebase::Beats,
+        );
+
+        assert_eq!(leds.render(now, None)[LED.index()], 0x10);
+        assert_eq!(leds.render(now, Some(4.1))[LED.index()], 0x40);
+        assert_eq!(leds.render(now, Some(4.5))[LED.index()], 0x10);
+
+        leds.flash(LED, 0x7F, Duration::from_secs(60));
+        assert_eq!(leds.render(now, Some(4.1))[LED.index()], 0x7F);
+        assert_eq!(leds.render(now, None)[LedId::Hotcue.index()], LED_DIM);
+        assert_eq!(leds.render(now, None)[0], LED_REPORT_ID);
+    }
+
+    #[test]
+    fn flashes_expire() {
+        let mut leds = LedCompositor::new();
+        leds.flash(LED, 0x7F, Duration::from_millis(100));
+        let now = Instant::now();
+        assert_eq!(leds.render(now, None)[LED.index()], 0x7F);
+        assert_eq!(
+            leds.render(now + Duration::from_millis(200), None)[LED.index()],
+            LED_DIM
+        );
+        assert!(!leds.is_active(Layer::Flash, LED));
+    }
+
+    #[test]
+    fn effects_follow_the_timeline() {
+        let metronome = Effect::Metronome {
+            accent: 3,
+            beat: 2,
+            quantum: 4.0,
+            accent_width: 0.1,
+            beat_width: 0.1,
+        };
+        assert_eq!(metronome.level(8.05, 0.0), Some(3));
+        assert_eq!(metronome.level(9.05, 0.0), Some(2));
+        assert_eq!(metronome.level(9.5, 0.0), None);
+
+        let pulse = Effect::Pulse {
+            low: 0,
+            high: 100,
+            period: 2.0,
+        };
+        assert_eq!(pulse.level(0.0, 0.0), Some(0));
+        assert_eq!(pulse.level(1.0, 0.0), Some(100));
+
+        let fade = Effect::Fade {
+            from: 100,
+            to: 0,
+            duration: 2.0,
+        };
+        assert_eq!(fade.level(10.0, 1.0), Some(50));
+        assert_eq!(fade.level(10.0, 5.0), Some(0));
+
+        let mut leds = LedCompositor::new();
+        let row = [LedId::Deck1_1, LedId::Deck1_2, LedId::Deck1_3];
+        leds.chase(Layer::Overlay, &row, 9, Some(1), 3.0, Timebase::Beats);
+        let frame = leds.render(Instant::now(), Some(1.5));
+        let levels: Vec<u8> = row.iter().map(|led| frame[led.index()]).collect();
+        assert_eq!(levels, vec![1, 9, 1]);
+    }
+}
@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+/// Signed number of detents between two 4-bit encoder readings.
+///
+/// The X1 reports each encoder as a nibble that wraps from 15 to 0, so the
+/// shortest way round is taken: `wrap_delta(0, 15) == 1` and
+/// `wrap_delta(15, 0) == -1`. Increasing values are clockwise.
+pub fn wrap_delta(new: u8, old: u8) -> i8 {
+    let diff = new.wrapping_sub(old) & 0x0F;
+    if diff >= 8 {
+        diff as i8 - 16
+    } else {
+        diff as i8
+    }
+}
+
+/// Velocity-based acceleration for encoder deltas.
+///
+/// Below `min_rate` detents per second a delta is passed through unchanged;
+/// at `max_rate` and above it is multiplied by `max_factor`, with a linear
+/// ramp in between.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EncoderAcceleration {
+    pub min_rate: f64,
+    pub max_rate: f64,
+    pub max_factor: f64,
+}
+
+impl Default for EncoderAcceleration {
+    fn default() -> Self {
+        Self {
+            min_rate: 8.0,
+            max_rate: 40.0,
+            max_factor: 8.0,
+        }
+    }
+}
+
+impl EncoderAcceleration {
+    /// Scale `delta` given the time since the previous movement of the same encoder.
+    pub fn apply(&self, delta: i8, since_last: Option<Duration>) -> i32 {
+        let delta = i32::from(delta);
+        let Some(elapsed) = since_last.filter(|d| !d.is_zero()) else {
+            return delta;
+        };
+
+        let rate = f64::from(delta.unsigned_abs()) / elapsed.as_secs_f64();
+        let factor = self.factor(rate);
+        let scaled = (f64::from(delta) * factor).round() as i32;
+        // Never let rounding swallow a detent or flip its direction.
+        if scaled.abs() < delta.abs() {
+            delta
+        } else {
+            scaled
+        }
+    }
+
+    fn factor(&self, rate: f64) -> f64 {
+        if rate <= self.min_rate || self.max_rate <= self.min_rate {
+            return if rate > self.min_rate {
+                self.max_factor.max(1.0)
+            } else {
+                1.0
+            };
+        }
+        let t = ((rate - self.min_rate) / (self.max_rate - self.min_rate)).min(1.0);
+        1.0 + t * (self.max_factor.max(1.0) - 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_in_both_directions() {
+        assert_eq!(wrap_delta(1, 0), 1);
+        assert_eq!(wrap_delta(0, 15), 1);
+        assert_eq!(wrap_delta(15, 0), -1);
+        assert_eq!(wrap_delta(13, 1), -4);
+        assert_eq!(wrap_delta(2, 14), 4);
+        assert_eq!(wrap_delta(7, 7), 0);
+    }
+
+    #[test]
+    fn accelerates_fast_turns_only() {
+        let accel = EncoderAcceleration::default();
+        assert_eq!(accel.apply(1, None), 1);
+        assert_eq!(accel.apply(-1, Some(Duration::from_millis(500))), -1);
+        assert_eq!(accel.apply(1, Some(Duration::from_millis(10))), 8);
+        assert_eq!(accel.apply(-2, Some(Duration::from_millis(10))), -16);
+
+        let halfway = accel.apply(1, Some(Duration::from_secs_f64(1.0 / 24.0)));
+        assert!(halfway > 1 && halfway < 8, "{halfway}");
+    }
+}
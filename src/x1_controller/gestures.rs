@@ -0,0 +1,408 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use super::x1_controller::{ButtonEvent, ButtonEventKind, ButtonId, Modifiers};
+
+/// Higher-level interpretation of button activity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GestureKind {
+    /// Pressed and released before the long-press time, and not pressed
+    /// again within the double-tap window; reported once that has passed.
+    Click,
+    /// A second click that started within the double-tap window of the first.
+    /// Neither click is reported on its own.
+    DoubleTap,
+    /// Held for at least the long-press time; fires while still held.
+    LongPress,
+    /// Every button of a configured chord is held at the same time.
+    Chord(Vec<ButtonId>),
+}
+
+/// A recognised gesture. For chords `id` is the button that completed it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GestureEvent {
+    pub id: ButtonId,
+    pub kind: GestureKind,
+    pub modifiers: Modifiers,
+}
+
+/// Timing and chord settings for gesture recognition.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GestureConfig {
+    pub long_press: Duration,
+    pub double_tap: Duration,
+    /// Button combinations reported as [`GestureKind::Chord`] whenever a
+    /// press completes one, in the order listed. Buttons taking part in a
+    /// chord do not produce clicks or long presses for that press.
+    pub chords: Vec<Vec<ButtonId>>,
+}
+
+impl Default for GestureConfig {
+    fn default() -> Self {
+        Self {
+            long_press: Duration::from_millis(600),
+            double_tap: Duration::from_millis(300),
+            chords: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ButtonTrack {
+    pressed_at: Option<Instant>,
+    modifiers: Modifiers,
+    long_fired: bool,
+    in_chord: bool,
+    /// Release time and modifiers of a click that may still become the
+    /// first half of a double tap.
+    pending_click: Option<(Instant, Modifiers)>,
+}
+
+/// Turns raw button transitions into clicks, double taps, long presses and chords.
+///
+/// Feed every [`ButtonEvent`] to [`on_button`](Self::on_button) and call
+/// [`poll`](Self::poll) regularly so long presses fire while a button is held
+/// and clicks are reported once they cannot turn into a double tap. Gestures
+/// of several buttons reported together come in [`ButtonId`] order.
+#[derive(Debug, Clone, Default)]
+pub struct GestureRecognizer {
+    config: GestureConfig,
+    buttons: BTreeMap<ButtonId, ButtonTrack>,
+}
+
+impl GestureRecognizer {
+    pub fn new(config: GestureConfig) -> Self {
+        Self {
+            config,
+            buttons: BTreeMap::new(),
+        }
+    }
+
+    pub fn set_config(&mut self, config: GestureConfig) {
+        self.config = config;
+    }
+
+    /// Forget all held buttons, e.g. after the device was reconnected.
+    pub fn reset(&mut self) {
+        self.buttons.clear();
+    }
+
+    /// Process a button transition and return the gestures it completes.
+    pub fn on_button(&mut self, event: ButtonEvent, now: Instant) -> Vec<GestureEvent> {
+        match event.kind {
+            ButtonEventKind::Pressed => self.on_press(event, now),
+            ButtonEventKind::Released => self.on_release(event, now),
+        }
+    }
+
+    /// Report clicks whose double-tap window has passed and long presses
+    /// that have matured since the last call.
+    pub fn poll(&mut self, now: Instant) -> Vec<GestureEvent> {
+        let mut gestures = Vec::new();
+        for (&id, track) in self.buttons.iter_mut() {
+            let Some(pressed_at) = track.pressed_at else {
+                // A second press inside the window is decided on its release.
+                if let Some((released, modifiers)) = track.pending_click {
+                    if now.saturating_duration_since(released) > self.config.double_tap {
+                        track.pending_click = None;
+                        gestures.push(click(id, modifiers));
+                    }
+                }
+                continue;
+            };
+            if track.long_fired || track.in_chord {
+                continue;
+            }
+            if now.saturating_duration_since(pressed_at) >= self.config.long_press {
+                if let Some((_, modifiers)) = track.pending_click.take() {
+                    gestures.push(click(id, modifiers));
+                }
+                track.long_fired = true;
+                gestures.push(GestureEvent {
+                    id,
+                    kind: GestureKind::LongPress,
+                    modifiers: track.modifiers,
+                });
+            }
+        }
+        gestures
+    }
+
+    fn on_press(&mut self, event: ButtonEvent, now: Instant) -> Vec<GestureEvent> {
+        let double_tap = self.config.double_tap;
+        let track = self.buttons.entry(event.id).or_default();
+        let mut gestures = Vec::new();
+        if let Some((released, modifiers)) = track.pending_click {
+            if now.saturating_duration_since(released) > double_tap {
+                track.pending_click = None;
+                gestures.push(click(event.id, modifiers));
+            }
+        }
+        track.pressed_at = Some(now);
+        track.modifiers = event.modifiers;
+        track.long_fired = false;
+        track.in_chord = false;
+
+        for chord in &self.config.chords {
+            if !chord.contains(&event.id) {
+                continue;
+            }
+            let complete = chord
+                .iter()
+                .all(|id| self.buttons.get(id).is_some_and(|t| t.pressed_at.is_some()));
+            if !complete {
+                continue;
+            }
+            for id in chord {
+                if let Some(track) = self.buttons.get_mut(id) {
+                    track.in_chord = true;
+                }
+            }
+            gestures.push(GestureEvent {
+                id: event.id,
+                kind: GestureKind::Chord(chord.clone()),
+                modifiers: event.modifiers,
+            });
+        }
+        gestures
+    }
+
+    fn on_release(&mut self, event: ButtonEvent, now: Instant) -> Vec<GestureEvent> {
+        let double_tap = self.config.double_tap;
+        let Some(track) = self.buttons.get_mut(&event.id) else {
+            return Vec::new();
+        };
+        let Some(pressed_at) = track.pressed_at.take() else {
+            return Vec::new();
+        };
+        if track.long_fired || track.in_chord {
+            // The earlier click was a click after all.
+            return track
+                .pending_click
+                .take()
+                .map(|(_, modifiers)| click(event.id, modifiers))
+                .into_iter()
+                .collect();
+        }
+
+        match track.pending_click.take() {
+            Some((released, _)) if pressed_at.saturating_duration_since(released) <= double_tap => {
+                vec![GestureEvent {
+                    id: event.id,
+                    kind: GestureKind::DoubleTap,
+                    modifiers: track.modifiers,
+                }]
+            }
+            earlier => {
+                track.pending_click = Some((now, track.modifiers));
+                earlier
+                    .map(|(_, modifiers)| click(event.id, modifiers))
+                    .into_iter()
+                    .collect()
+            }
+        }
+    }
+}
+
+fn click(id: ButtonId, modifiers: Modifiers) -> GestureEvent {
+    GestureEvent {
+        id,
+        kind: GestureKind::Click,
+        modifiers,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(id: ButtonId, pressed: bool) -> ButtonEvent {
+        ButtonEvent {
+            id,
+            kind: if pressed {
+                ButtonEventKind::Pressed
+            } else {
+                ButtonEventKind::Released
+            },
+            modifiers: Modifiers::default(),
+        }
+    }
+
+    fn kinds(gestures: Vec<GestureEvent>) -> Vec<GestureKind> {
+        gestures.into_iter().map(|g| g.kind).collect()
+    }
+
+    #[test]
+    fn click_and_double_tap() {
+        let mut rec = GestureRecognizer::default();
+        let t0 = Instant::now();
+        let ms = |n| t0 + Duration::from_millis(n);
+
+        assert!(rec
+            .on_button(event(ButtonId::Deck1Play, true), ms(0))
+            .is_empty());
+        assert!(rec
+            .on_button(event(ButtonId::Deck1Play, false), ms(80))
+            .is_empty());
+        assert!(rec.poll(ms(190)).is_empty());
+        assert!(rec
+            .on_button(event(ButtonId::Deck1Play, true), ms(200))
+            .is_empty());
+        assert_eq!(
+            kinds(rec.on_button(event(ButtonId::Deck1Play, false), ms(260))),
+            vec![GestureKind::DoubleTap]
+        );
+        // Neither half of the double tap is reported as a click.
+        assert!(rec.poll(ms(1000)).is_empty());
+
+        // A single tap is a click once the double-tap window has passed.
+        rec.on_button(event(ButtonId::Deck1Play, true), ms(1100));
+        assert!(rec
+            .on_button(event(ButtonId::Deck1Play, false), ms(1150))
+            .is_empty());
+        assert!(rec.poll(ms(1400)).is_empty());
+        assert_eq!(kinds(rec.poll(ms(1500))), vec![GestureKind::Click]);
+        assert!(rec.poll(ms(2000)).is_empty());
+
+        // So is a tap followed by a late second press.
+        rec.on_button(event(ButtonId::Deck1Play, true), ms(2100));
+        rec.on_button(event(ButtonId::Deck1Play, false), ms(2150));
+        assert_eq!(
+            kinds(rec.on_button(event(ButtonId::Deck1Play, true), ms(2500))),
+            vec![GestureKind::Click]
+        );
+    }
+
+    #[test]
+    fn long_press_fires_while_held_and_suppresses_click() {
+        let mut rec = GestureRecognizer::default();
+        let t0 = Instant::now();
+        rec.on_button(event(ButtonId::Deck1Play, true), t0);
+
+        assert!(rec.poll(t0 + Duration::from_millis(300)).is_empty());
+        assert_eq!(
+            kinds(rec.poll(t0 + Duration::from_millis(700))),
+            vec![GestureKind::LongPress]
+        );
+        assert!(rec.poll(t0 + Duration::from_millis(900)).is_empty());
+        assert!(rec
+            .on_button(
+                event(ButtonId::Deck1Play, false),
+                t0 + Duration::from_secs(1)
+            )
+            .is_empty());
+    }
+
+    #[test]
+    fn chord_consumes_member_buttons() {
+        let chord = vec![ButtonId::Hotcue, ButtonId::Deck1Play];
+        let mut rec = GestureRecognizer::new(GestureConfig {
+            chords: vec![chord.clone()],
+            ..GestureConfig::default()
+        });
+        let t0 = Instant::now();
+
+        assert!(rec.on_button(event(ButtonId::Hotcue, true), t0).is_empty());
+        assert_eq!(
+            kinds(rec.on_button(event(ButtonId::Deck1Play, true), t0)),
+            vec![GestureKind::Chord(chord)]
+        );
+        assert!(rec.poll(t0 + Duration::from_secs(2)).is_empty());
+        assert!(rec.on_button(event(ButtonId::Hotcue, false), t0).is_empty());
+        assert!(rec
+            .on_button(event(ButtonId::Deck1Play, false), t0)
+            .is_empty());
+    }
+
+    #[test]
+    fn overlapping_chords_share_a_held_button() {
+        let first = vec![ButtonId::Hotcue, ButtonId::Deck1Play];
+        let second = vec![ButtonId::Hotcue, ButtonId::Deck2Play];
+        let mut rec = GestureRecognizer::new(GestureConfig {
+            chords: vec![first.clone(), second.clone()],
+            ..GestureConfig::default()
+        });
+        let t0 = Instant::now();
+
+        // Holding the shared button, each partner completes its own chord.
+        rec.on_button(event(ButtonId::Hotcue, true), t0);
+        assert_eq!(
+            kinds(rec.on_button(event(ButtonId::Deck1Play, true), t0)),
+            vec![GestureKind::Chord(first.clone())]
+        );
+        assert!(rec
+            .on_button(event(ButtonId::Deck1Play, false), t0)
+            .is_empty());
+        assert_eq!(
+            kinds(rec.on_button(event(ButtonId::Deck2Play, true), t0)),
+            vec![GestureKind::Chord(second.clone())]
+        );
+        assert!(rec.on_button(event(ButtonId::Hotcue, false), t0).is_empty());
+
+        // A press that completes both reports them in the configured order.
+        rec.on_button(event(ButtonId::Deck1Play, true), t0);
+        assert_eq!(
+            kinds(rec.on_button(event(ButtonId::Hotcue, true), t0)),
+            vec![GestureKind::Chord(first), GestureKind::Chord(second)]
+        );
+        for id in [ButtonId::Hotcue, ButtonId::Deck1Play, ButtonId::Deck2Play] {
+            assert!(rec.on_button(event(id, false), t0).is_empty());
+        }
+        assert!(rec.poll(t0 + Duration::from_secs(2)).is_empty());
+    }
+
+    #[test]
+    fn chord_member_pressed_again_repeats_the_chord() {
+        let chord = vec![ButtonId::Hotcue, ButtonId::Deck1Play];
+        let mut rec = GestureRecognizer::new(GestureConfig {
+            chords: vec![chord.clone()],
+            ..GestureConfig::default()
+        });
+        let t0 = Instant::now();
+        let ms = |n| t0 + Duration::from_millis(n);
+
+        rec.on_button(event(ButtonId::Hotcue, true), ms(0));
+        rec.on_button(event(ButtonId::Deck1Play, true), ms(0));
+        rec.on_button(event(ButtonId::Deck1Play, false), ms(100));
+        assert_eq!(
+            kinds(rec.on_button(event(ButtonId::Deck1Play, true), ms(200))),
+            vec![GestureKind::Chord(chord)]
+        );
+        // Neither the re-press nor the two presses make a double tap.
+        assert!(rec
+            .on_button(event(ButtonId::Deck1Play, false), ms(250))
+            .is_empty());
+        assert!(rec
+            .on_button(event(ButtonId::Hotcue, false), ms(300))
+            .is_empty());
+        assert!(rec.poll(ms(2000)).is_empty());
+
+        // On its own the member is a plain button again.
+        rec.on_button(event(ButtonId::Deck1Play, true), ms(3000));
+        rec.on_button(event(ButtonId::Deck1Play, false), ms(3050));
+        assert_eq!(kinds(rec.poll(ms(3400))), vec![GestureKind::Click]);
+    }
+
+    #[test]
+    fn gestures_of_several_buttons_come_in_button_order() {
+        let mut rec = GestureRecognizer::default();
+        let t0 = Instant::now();
+        let ids = [ButtonId::Deck2Sync, ButtonId::Shift, ButtonId::Deck1On];
+        for id in ids {
+            rec.on_button(event(id, true), t0);
+            rec.on_button(event(id, false), t0);
+        }
+
+        let clicked: Vec<ButtonId> = rec
+            .poll(t0 + Duration::from_secs(1))
+            .into_iter()
+            .map(|g| g.id)
+            .collect();
+        assert_eq!(
+            clicked,
+            vec![ButtonId::Deck1On, ButtonId::Shift, ButtonId::Deck2Sync]
+        );
+    }
+}
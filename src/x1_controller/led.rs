@@ -0,0 +1,172 @@
+use serde::Deserialize;
+
+use super::x1_controller::ButtonId;
+
+/// Command byte that starts every LED frame: the X1 Mk1 takes its LED
+/// brightness table as a vendor command (`EP1_CMD_DIMM_LEDS` in the Linux
+/// `snd-usb-caiaq` driver) rather than a HID report.
+pub const LED_REPORT_ID: u8 = 0x0C;
+
+/// Every LED on the Kontrol X1 Mk1.
+///
+/// LEDs are named after the button they sit in; the encoder push buttons have
+/// no LED. [`LedId::index`] gives the byte in the 32-byte LED frame.
+///
+/// The order is the X1's LED table as listed by the Linux `snd-usb-caiaq`
+/// driver (`kontrolx1_controller` in `sound/usb/caiaq/control.c`), shifted by
+/// one for the command byte; Deck A Sync at 23 is the LED this app has always
+/// flashed on taps. The driver also lists a green Shift LED after the white
+/// one, in byte 31, which is left at its initial level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+pub enum LedId {
+    Deck2_3,
+    Deck2_2,
+    Deck2_1,
+    Deck2On,
+    Deck1_3,
+    Deck1_2,
+    Deck1_1,
+    Deck1On,
+    Deck2Sync,
+    Deck2Play,
+    Deck2CupAbs,
+    Deck2CueRel,
+    Deck2BeatRight,
+    Deck2BeatLeft,
+    Deck2Out,
+    Deck2In,
+    Deck1Out,
+    Deck1In,
+    Deck1BeatRight,
+    Deck1BeatLeft,
+    Deck1CupAbs,
+    Deck1CueRel,
+    Deck1Sync,
+    Deck1Play,
+    Deck1Fx1,
+    Deck1Fx2,
+    Deck2Fx1,
+    Deck2Fx2,
+    Hotcue,
+    Shift,
+}
+
+impl LedId {
+    /// Every LED, in frame order.
+    pub const ALL: [LedId; 30] = [
+        LedId::Deck2_3,
+        LedId::Deck2_2,
+        LedId::Deck2_1,
+        LedId::Deck2On,
+        LedId::Deck1_3,
+        LedId::Deck1_2,
+        LedId::Deck1_1,
+        LedId::Deck1On,
+        LedId::Deck2Sync,
+        LedId::Deck2Play,
+        LedId::Deck2CupAbs,
+        LedId::Deck2CueRel,
+        LedId::Deck2BeatRight,
+        LedId::Deck2BeatLeft,
+        LedId::Deck2Out,
+        LedId::Deck2In,
+        LedId::Deck1Out,
+        LedId::Deck1In,
+        LedId::Deck1BeatRight,
+        LedId::Deck1BeatLeft,
+        LedId::Deck1CupAbs,
+        LedId::Deck1CueRel,
+        LedId::Deck1Sync,
+        LedId::Deck1Play,
+        LedId::Deck1Fx1,
+        LedId::Deck1Fx2,
+        LedId::Deck2Fx1,
+        LedId::Deck2Fx2,
+        LedId::Hotcue,
+        LedId::Shift,
+    ];
+
+    /// Byte offset of this LED in the LED frame (byte 0 is
+    /// [`LED_REPORT_ID`]).
+    pub fn index(self) -> usize {
+        self as usize + 1
+    }
+
+    /// The LED stored at a frame offset, if any.
+    pub fn from_index(index: usize) -> Option<LedId> {
+        index.checked_sub(1).and_then(|i| Self::ALL.get(i).copied())
+    }
+
+    /// The LED inside a button, or `None` for the encoder push buttons.
+    pub fn for_button(id: ButtonId) -> Option<LedId> {
+        Some(match id {
+            ButtonId::Deck1On => LedId::Deck1On,
+            ButtonId::Deck2On => LedId::Deck2On,
+            ButtonId::Deck1_1 => LedId::Deck1_1,
+            ButtonId::Deck2_1 => LedId::Deck2_1,
+            ButtonId::Deck1_2 => LedId::Deck1_2,
+            ButtonId::Deck2_2 => LedId::Deck2_2,
+            ButtonId::Deck1_3 => LedId::Deck1_3,
+            ButtonId::Deck2_3 => LedId::Deck2_3,
+            ButtonId::Shift => LedId::Shift,
+            ButtonId::Deck1Fx1 => LedId::Deck1Fx1,
+            ButtonId::Deck1Fx2 => LedId::Deck1Fx2,
+            ButtonId::Deck2Fx1 => LedId::Deck2Fx1,
+            ButtonId::Deck2Fx2 => LedId::Deck2Fx2,
+            ButtonId::Hotcue => LedId::Hotcue,
+            ButtonId::Deck1In => LedId::Deck1In,
+            ButtonId::Deck1Out => LedId::Deck1Out,
+            ButtonId::Deck2In => LedId::Deck2In,
+            ButtonId::Deck2Out => LedId::Deck2Out,
+            ButtonId::Deck1BeatLeft => LedId::Deck1BeatLeft,
+            ButtonId::Deck1BeatRight => LedId::Deck1BeatRight,
+            ButtonId::Deck2BeatLeft => LedId::Deck2BeatLeft,
+            ButtonId::Deck2BeatRight => LedId::Deck2BeatRight,
+            ButtonId::Deck1CueRel => LedId::Deck1CueRel,
+            ButtonId::Deck1CupAbs => LedId::Deck1CupAbs,
+            ButtonId::Deck2CueRel => LedId::Deck2CueRel,
+            ButtonId::Deck2CupAbs => LedId::Deck2CupAbs,
+            ButtonId::Deck1Play => LedId::Deck1Play,
+            ButtonId::Deck1Sync => LedId::Deck1Sync,
+            ButtonId::Deck2Play => LedId::Deck2Play,
+            ButtonId::Deck2Sync => LedId::Deck2Sync,
+            ButtonId::Deck1EncLoad
+            | ButtonId::Deck2EncLoad
+            | ButtonId::Deck1EncLoop
+            | ButtonId::Deck2EncLoop => return None,
+        })
+    }
+}
+
+impl ButtonId {
+    /// The LED inside this button, if it has one.
+    pub fn led(self) -> Option<LedId> {
+        LedId::for_button(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indices_are_unique_and_skip_the_report_id() {
+        for (i, led) in LedId::ALL.iter().enumerate() {
+            assert_eq!(led.index(), i + 1);
+            assert_eq!(LedId::from_index(led.index()), Some(*led));
+        }
+        assert_eq!(LedId::from_index(0), None);
+        assert_eq!(LedId::Deck1Sync.index(), 23);
+        assert_eq!(LedId::Deck2_3.index(), 1);
+        assert_eq!(LedId::Deck1On.index(), 8);
+        assert_eq!(LedId::Deck2In.index(), 16);
+        assert_eq!(LedId::Shift.index(), 30);
+    }
+
+    #[test]
+    fn buttons_map_to_their_led() {
+        assert_eq!(ButtonId::Deck1Sync.led(), Some(LedId::Deck1Sync));
+        assert_eq!(ButtonId::Hotcue.led(), Some(LedId::Hotcue));
+        assert_eq!(ButtonId::Deck1EncLoad.led(), None);
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rusb::Error;
+
+use super::transport::{X1Transport, LED_FRAME_LEN, REPORT_LEN};
+
+/// In-memory transport for exercising the controller without hardware.
+///
+/// Clones share the same state, so a test can hand one clone to the
+/// controller and keep another to script reports and inspect LED frames.
+#[derive(Clone, Default)]
+pub struct MockTransport {
+    inner: Arc<Mutex<MockState>>,
+}
+
+#[derive(Default)]
+struct MockState {
+    reads: VecDeque<rusb::Result<[u8; REPORT_LEN]>>,
+    led_frames: Vec<[u8; LED_FRAME_LEN]>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a raw input report to be returned by the next read.
+    pub fn push_report(&self, report: [u8; REPORT_LEN]) {
+        self.lock().reads.push_back(Ok(report));
+    }
+
+    /// Queue an error to be returned by the next read.
+    pub fn push_error(&self, err: Error) {
+        self.lock().reads.push_back(Err(err));
+    }
+
+    /// Number of queued reads that have not been consumed yet.
+    pub fn pending_reads(&self) -> usize {
+        self.lock().reads.len()
+    }
+
+    /// Every LED frame written so far, oldest first.
+    pub fn led_frames(&self) -> Vec<[u8; LED_FRAME_LEN]> {
+        self.lock().led_frames.clone()
+    }
+
+    /// The most recently written LED frame.
+    pub fn last_led_frame(&self) -> Option<[u8; LED_FRAME_LEN]> {
+        self.lock().led_frames.last().copied()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl X1Transport for MockTransport {
+    fn read_report(
+        &mut self,
+        buf: &mut [u8; REPORT_LEN],
+        _timeout: Duration,
+    ) -> rusb::Result<usize> {
+        match self.lock().reads.pop_front() {
+            Some(Ok(report)) => {
+                *buf = report;
+                Ok(REPORT_LEN)
+            }
+            Some(Err(err)) => Err(err),
+            None => Err(Error::Timeout),
+        }
+    }
+
+    fn write_leds(&mut self, frame: &[u8; LED_FRAME_LEN], _timeout: Duration) -> rusb::Result<()> {
+        self.lock().led_frames.push(*frame);
+        Ok(())
+    }
+
+    fn read_unlock_ack(&mut self, _timeout: Duration) -> rusb::Result<()> {
+        Ok(())
+    }
+}
@@ -1,10 +1,35 @@
+//! Driver for the Native Instruments Kontrol X1 Mk1.
+//!
+//! [`X1Controller`] polls input reports through an [`X1Transport`], parses
+//! them into an [`X1State`], and reports button, encoder, pot, gesture and
+//! connection events to callbacks. LEDs are set by [`LedId`] or composed with
+//! an [`LedCompositor`].
+
+mod capture;
+mod compositor;
+mod encoder;
+mod gestures;
+mod led;
+mod mock;
+mod pot_filter;
+mod transport;
+#[allow(clippy::module_inception)]
 mod x1_controller;
 mod x1_state;
 
-#[allow(unused_imports)]
+pub use capture::{
+    parse_capture, read_capture, CaptureRecord, CaptureTransport, CaptureWriter, Direction,
+    ReplayTransport,
+};
+pub use compositor::{Effect, Layer, LedCompositor, Timebase};
+pub use encoder::{wrap_delta, EncoderAcceleration};
+pub use gestures::{GestureConfig, GestureEvent, GestureKind, GestureRecognizer};
+pub use led::{LedId, LED_REPORT_ID};
+pub use mock::MockTransport;
+pub use pot_filter::{PotCalibration, PotFilterConfig, POT_RAW_MAX};
+pub use transport::{UsbTransport, X1Transport, LED_FRAME_LEN, REPORT_LEN};
 pub use x1_controller::{
-    ButtonEvent, ButtonEventKind, ButtonId, EncoderEvent, EncoderId, LedHandle, Modifiers,
-    PotEvent, PotId, Timestamp, X1Controller, LED_BRIGHT, LED_DIM,
+    ButtonEvent, ButtonEventKind, ButtonId, ConnectionEvent, EncoderEvent, EncoderId, LedHandle,
+    Modifiers, PotEvent, PotId, Timestamp, X1Controller, LED_BRIGHT, LED_DIM,
 };
-#[allow(unused_imports)]
 pub use x1_state::X1State;
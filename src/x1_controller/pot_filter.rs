@@ -0,0 +1,182 @@
+/// Largest raw value the X1's 12-bit pot ADC reports.
+pub const POT_RAW_MAX: u16 = 0x0FFF;
+
+const POT_COUNT: usize = 8;
+
+/// Raw end points of a single pot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PotCalibration {
+    pub min: u16,
+    pub max: u16,
+}
+
+impl Default for PotCalibration {
+    fn default() -> Self {
+        Self {
+            min: 0,
+            max: POT_RAW_MAX,
+        }
+    }
+}
+
+impl PotCalibration {
+    /// Map a raw reading onto 0.0–1.0 within the calibrated range.
+    pub fn normalize(&self, value: u16) -> f32 {
+        if self.max <= self.min {
+            return 0.0;
+        }
+        let span = f32::from(self.max - self.min);
+        (f32::from(value.saturating_sub(self.min)) / span).clamp(0.0, 1.0)
+    }
+}
+
+/// Settings for the pot filtering stage.
+///
+/// * `deadband` – a pot has to move more than this many raw steps away from
+///   the last reported value before a new event fires. The calibrated end
+///   points are always reachable.
+/// * `smoothing` – exponential smoothing factor in `0.0..1.0`; `0.0` disables
+///   smoothing, values closer to `1.0` favour the previous reading more.
+/// * `calibration` – per-pot raw range, indexed in [`PotId`](super::PotId) order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PotFilterConfig {
+    pub deadband: u16,
+    pub smoothing: f32,
+    pub calibration: [PotCalibration; POT_COUNT],
+}
+
+impl Default for PotFilterConfig {
+    fn default() -> Self {
+        Self {
+            deadband: 4,
+            smoothing: 0.0,
+            calibration: [PotCalibration::default(); POT_COUNT],
+        }
+    }
+}
+
+/// A filtered pot change ready to be reported.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct PotChange {
+    pub value: u16,
+    pub previous: u16,
+    pub normalized: f32,
+}
+
+/// Per-pot hysteresis, smoothing and calibration state.
+#[derive(Debug, Clone)]
+pub(crate) struct PotFilter {
+    config: PotFilterConfig,
+    smoothed: [f32; POT_COUNT],
+    reported: [u16; POT_COUNT],
+}
+
+impl PotFilter {
+    pub fn new(config: PotFilterConfig) -> Self {
+        Self {
+            config,
+            smoothed: [0.0; POT_COUNT],
+            reported: [0; POT_COUNT],
+        }
+    }
+
+    pub fn config(&self) -> &PotFilterConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: PotFilterConfig) {
+        self.config = config;
+    }
+
+    /// Start from the given readings without reporting them.
+    pub fn reset(&mut self, readings: &[u16; POT_COUNT]) {
+        for (i, &raw) in readings.iter().enumerate() {
+            self.smoothed[i] = f32::from(raw);
+            self.reported[i] = raw;
+        }
+    }
+
+    /// Feed a raw reading; returns a change when it should be reported.
+    pub fn update(&mut self, index: usize, raw: u16) -> Option<PotChange> {
+        let smoothing = self.config.smoothing.clamp(0.0, 0.99);
+        let smoothed = self.smoothed.get_mut(index)?;
+        *smoothed += (1.0 - smoothing) * (f32::from(raw) - *smoothed);
+        let value = smoothed.round() as u16;
+
+        let previous = self.reported[index];
+        if value == previous {
+            return None;
+        }
+        // Reaching an end point skips the deadband, but readings beyond a
+        // calibrated end all mean the same and are not reported again.
+        let calibration = self.config.calibration[index];
+        let normalized = calibration.normalize(value);
+        let at_end = normalized == 0.0 || normalized == 1.0;
+        if at_end && normalized == calibration.normalize(previous) {
+            return None;
+        }
+        if value.abs_diff(previous) <= self.config.deadband && !at_end {
+            return None;
+        }
+
+        self.reported[index] = value;
+        Some(PotChange {
+            value,
+            previous,
+            normalized,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deadband_suppresses_jitter_but_reaches_end_points() {
+        let mut filter = PotFilter::new(PotFilterConfig::default());
+        filter.reset(&[2000; POT_COUNT]);
+
+        assert_eq!(filter.update(0, 2003), None);
+        assert_eq!(filter.update(0, 1997), None);
+        let change = filter.update(0, 2010).unwrap();
+        assert_eq!((change.value, change.previous), (2010, 2000));
+
+        filter.reset(&[POT_RAW_MAX - 2; POT_COUNT]);
+        let change = filter.update(1, POT_RAW_MAX).unwrap();
+        assert_eq!(change.normalized, 1.0);
+    }
+
+    #[test]
+    fn jitter_beyond_a_calibrated_end_is_suppressed() {
+        let mut config = PotFilterConfig::default();
+        config.calibration[0] = PotCalibration { min: 100, max: 300 };
+        let mut filter = PotFilter::new(config);
+        filter.reset(&[150; POT_COUNT]);
+
+        assert_eq!(filter.update(0, 95).unwrap().normalized, 0.0);
+        for raw in [90, 93, 95, 91, 98, 100] {
+            assert_eq!(filter.update(0, raw), None, "{raw}");
+        }
+        let change = filter.update(0, 104).unwrap();
+        assert_eq!(change.previous, 95);
+        assert!(change.normalized > 0.0);
+    }
+
+    #[test]
+    fn smoothing_and_calibration() {
+        let mut config = PotFilterConfig {
+            deadband: 0,
+            smoothing: 0.5,
+            ..PotFilterConfig::default()
+        };
+        config.calibration[0] = PotCalibration { min: 100, max: 300 };
+        let mut filter = PotFilter::new(config);
+        filter.reset(&[100; POT_COUNT]);
+
+        let change = filter.update(0, 300).unwrap();
+        assert_eq!(change.value, 200);
+        assert!((change.normalized - 0.5).abs() < 1e-6);
+        assert_eq!(filter.update(0, 300).unwrap().value, 250);
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use rusb::{Context, DeviceHandle, Error, UsbContext};
+
+/// Size of an X1 input report in bytes.
+pub const REPORT_LEN: usize = 24;
+/// Size of an LED output frame in bytes.
+pub const LED_FRAME_LEN: usize = 32;
+
+/// Byte-level link between [`X1Controller`](super::X1Controller) and a device.
+///
+/// Implementations only move reports around; parsing and callback dispatch
+/// stay in the controller. Errors use `rusb::Error` so the controller can treat
+/// every transport the same way (`Timeout` means "nothing to read").
+pub trait X1Transport: Send {
+    /// Read one input report into `buf`, returning the number of bytes read.
+    fn read_report(&mut self, buf: &mut [u8; REPORT_LEN], timeout: Duration)
+        -> rusb::Result<usize>;
+
+    /// Send a full LED frame to the device.
+    fn write_leds(&mut self, frame: &[u8; LED_FRAME_LEN], timeout: Duration) -> rusb::Result<()>;
+
+    /// Consume the acknowledgement the X1 sends after each LED frame.
+    fn read_unlock_ack(&mut self, timeout: Duration) -> rusb::Result<()>;
+}
+
+impl<T: X1Transport + ?Sized> X1Transport for Box<T> {
+    fn read_report(
+        &mut self,
+        buf: &mut [u8; REPORT_LEN],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        (**self).read_report(buf, timeout)
+    }
+
+    fn write_leds(&mut self, frame: &[u8; LED_FRAME_LEN], timeout: Duration) -> rusb::Result<()> {
+        (**self).write_leds(frame, timeout)
+    }
+
+    fn read_unlock_ack(&mut self, timeout: Duration) -> rusb::Result<()> {
+        (**self).read_unlock_ack(timeout)
+    }
+}
+
+/// Default transport talking to a Kontrol X1 Mk1 over libusb bulk endpoints.
+pub struct UsbTransport {
+    handle: DeviceHandle<Context>,
+}
+
+impl UsbTransport {
+    /// Find, open and claim the first Kontrol X1 Mk1 on the bus.
+    pub fn open(context: &Context) -> rusb::Result<Option<Self>> {
+        for device in context.devices()?.iter() {
+            let desc = device.device_descriptor()?;
+            if desc.vendor_id() != X1_VENDOR_ID || desc.product_id() != X1_PRODUCT_ID {
+                continue;
+            }
+
+            let handle = device.open()?;
+            // Not supported on every platform; claiming below reports real failures.
+            let _ = handle.set_auto_detach_kernel_driver(true);
+            handle.set_active_configuration(1)?;
+            handle.claim_interface(0)?;
+            handle.set_alternate_setting(0, 0)?;
+            return Ok(Some(Self { handle }));
+        }
+        Ok(None)
+    }
+}
+
+impl X1Transport for UsbTransport {
+    fn read_report(
+        &mut self,
+        buf: &mut [u8; REPORT_LEN],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        self.handle.read_bulk(USB_READ_ENDPOINT, buf, timeout)
+    }
+
+    fn write_leds(&mut self, frame: &[u8; LED_FRAME_LEN], timeout: Duration) -> rusb::Result<()> {
+        self.handle
+            .write_bulk(USB_WRITE_ENDPOINT, frame, timeout)
+            .map(|_| ())
+    }
+
+    fn read_unlock_ack(&mut self, timeout: Duration) -> rusb::Result<()> {
+        let mut ack = [0u8; 1];
+        match self
+            .handle
+            .read_bulk(USB_UNLOCK_ENDPOINT, &mut ack, timeout)
+        {
+            Ok(_) | Err(Error::Timeout) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+const X1_VENDOR_ID: u16 = 0x17cc;
+const X1_PRODUCT_ID: u16 = 0x2305;
+const USB_READ_ENDPOINT: u8 = 0x84;
+const USB_WRITE_ENDPOINT: u8 = 0x01;
+const USB_UNLOCK_ENDPOINT: u8 = 0x81;
@@ -1,7 +1,13 @@
 use std::time::{Duration, Instant};
 
-use rusb::{Context, DeviceHandle, Error, UsbContext};
+use rusb::{Context, Error};
+use serde::Deserialize;
 
+use super::encoder::{wrap_delta, EncoderAcceleration};
+use super::gestures::{GestureConfig, GestureEvent, GestureRecognizer};
+use super::led::{LedId, LED_REPORT_ID};
+use super::pot_filter::{PotCalibration, PotFilter, PotFilterConfig};
+use super::transport::{UsbTransport, X1Transport, LED_FRAME_LEN, REPORT_LEN};
 use super::x1_state::X1State;
 
 /// Timestamp used for controller events.
@@ -14,6 +20,7 @@ pub enum ButtonEventKind {
     Released,
 }
 
+/// Modifier buttons held when an event fired.
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub struct Modifiers {
     pub shift: bool,
@@ -27,7 +34,7 @@ impl Modifiers {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
 pub enum ButtonId {
     Deck1On,
     Deck2On,
@@ -65,7 +72,47 @@ pub enum ButtonId {
     Deck2Sync,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+impl ButtonId {
+    /// Every button, in declaration order.
+    pub const ALL: [ButtonId; 34] = [
+        ButtonId::Deck1On,
+        ButtonId::Deck2On,
+        ButtonId::Deck1_1,
+        ButtonId::Deck2_1,
+        ButtonId::Deck1_2,
+        ButtonId::Deck2_2,
+        ButtonId::Deck1_3,
+        ButtonId::Deck2_3,
+        ButtonId::Deck1EncLoad,
+        ButtonId::Shift,
+        ButtonId::Deck2EncLoad,
+        ButtonId::Deck1Fx1,
+        ButtonId::Deck1Fx2,
+        ButtonId::Deck2Fx1,
+        ButtonId::Deck2Fx2,
+        ButtonId::Deck1EncLoop,
+        ButtonId::Hotcue,
+        ButtonId::Deck2EncLoop,
+        ButtonId::Deck1In,
+        ButtonId::Deck1Out,
+        ButtonId::Deck2In,
+        ButtonId::Deck2Out,
+        ButtonId::Deck1BeatLeft,
+        ButtonId::Deck1BeatRight,
+        ButtonId::Deck2BeatLeft,
+        ButtonId::Deck2BeatRight,
+        ButtonId::Deck1CueRel,
+        ButtonId::Deck1CupAbs,
+        ButtonId::Deck2CueRel,
+        ButtonId::Deck2CupAbs,
+        ButtonId::Deck1Play,
+        ButtonId::Deck1Sync,
+        ButtonId::Deck2Play,
+        ButtonId::Deck2Sync,
+    ];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
 pub enum EncoderId {
     Deck1Browse,
     Deck2Browse,
@@ -73,7 +120,7 @@ pub enum EncoderId {
     Deck2Loop,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
 pub enum PotId {
     Deck1DryWet,
     Deck1_1,
@@ -85,6 +132,31 @@ pub enum PotId {
     Deck2_3,
 }
 
+impl PotId {
+    /// Every pot, in the order used for per-pot settings.
+    pub const ALL: [PotId; 8] = [
+        PotId::Deck1DryWet,
+        PotId::Deck1_1,
+        PotId::Deck1_2,
+        PotId::Deck1_3,
+        PotId::Deck2DryWet,
+        PotId::Deck2_1,
+        PotId::Deck2_2,
+        PotId::Deck2_3,
+    ];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Connection transitions reported by a supervised controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    Connected,
+    Disconnected,
+}
+
 /// Information about a button transition.
 #[derive(Debug, Clone, Copy)]
 pub struct ButtonEvent {
@@ -93,48 +165,94 @@ pub struct ButtonEvent {
     pub modifiers: Modifiers,
 }
 
+impl EncoderId {
+    /// Every encoder, in declaration order.
+    pub const ALL: [EncoderId; 4] = [
+        EncoderId::Deck1Browse,
+        EncoderId::Deck2Browse,
+        EncoderId::Deck1Loop,
+        EncoderId::Deck2Loop,
+    ];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
 /// Information about an encoder value change.
-#[allow(dead_code)]
+///
+/// `value`/`previous` are the raw 4-bit readings. `delta` is the signed,
+/// wraparound-corrected number of detents (positive is clockwise) and
+/// `accelerated_delta` is `delta` after the controller's acceleration, or
+/// equal to it when acceleration is off.
 #[derive(Debug, Clone, Copy)]
 pub struct EncoderEvent {
     pub id: EncoderId,
     pub value: u8,
     pub previous: u8,
+    pub delta: i8,
+    pub accelerated_delta: i32,
     pub modifiers: Modifiers,
 }
 
 /// Information about a potentiometer value change.
-#[allow(dead_code)]
+///
+/// `value`/`previous` are filtered readings (see [`PotFilterConfig`]), `raw`
+/// is the unfiltered reading from the report, and `normalized` maps `value`
+/// onto 0.0–1.0 using the pot's calibration.
 #[derive(Debug, Clone, Copy)]
 pub struct PotEvent {
     pub id: PotId,
     pub value: u16,
     pub previous: u16,
+    pub raw: u16,
+    pub normalized: f32,
     pub modifiers: Modifiers,
 }
 
+type ButtonCallback =
+    Box<dyn FnMut(&X1State, ButtonEvent, Timestamp, &mut LedHandle) + Send + 'static>;
+type EncoderCallback =
+    Box<dyn FnMut(&X1State, EncoderEvent, Timestamp, &mut LedHandle) + Send + 'static>;
+type PotCallback = Box<dyn FnMut(&X1State, PotEvent, Timestamp, &mut LedHandle) + Send + 'static>;
+type GestureCallback =
+    Box<dyn FnMut(&X1State, GestureEvent, Timestamp, &mut LedHandle) + Send + 'static>;
+type ConnectionCallback = Box<dyn FnMut(ConnectionEvent, Timestamp) + Send + 'static>;
+type Connector<T> = Box<dyn FnMut() -> rusb::Result<Option<T>> + Send + 'static>;
+
 /// High-level interface for working with the X1 controller.
 ///
 /// The controller polls the USB endpoint, updates LED feedback, and
 /// notifies registered callbacks when buttons, encoders, or pots change.
-pub struct X1Controller {
-    _context: Context,
-    handle: DeviceHandle<Context>,
-    input_buf: [u8; 24],
+///
+/// A controller created with [`X1Controller::connect_supervised`] survives the
+/// device going away: USB errors drop the transport, and subsequent polls keep
+/// looking for the X1 until it can be claimed again.
+///
+/// The device is reached through an [`X1Transport`]; [`UsbTransport`] is used
+/// unless another one is supplied via [`X1Controller::with_transport`].
+pub struct X1Controller<T: X1Transport = UsbTransport> {
+    transport: Option<T>,
+    connector: Option<Connector<T>>,
+    next_connect_attempt: Instant,
+    input_buf: [u8; REPORT_LEN],
     timeout: Duration,
-    leds: [u8; 32],
+    leds: [u8; LED_FRAME_LEN],
     leds_dirty: bool,
     last_state: X1State,
     initialized: bool,
-    button_callback:
-        Option<Box<dyn FnMut(&X1State, ButtonEvent, Timestamp, &mut LedHandle) + Send + 'static>>,
-    encoder_callback:
-        Option<Box<dyn FnMut(&X1State, EncoderEvent, Timestamp, &mut LedHandle) + Send + 'static>>,
-    pot_callback:
-        Option<Box<dyn FnMut(&X1State, PotEvent, Timestamp, &mut LedHandle) + Send + 'static>>,
+    encoder_acceleration: Option<EncoderAcceleration>,
+    encoder_last_moved: [Option<Instant>; 4],
+    pot_filter: PotFilter,
+    gestures: GestureRecognizer,
+    button_callback: Option<ButtonCallback>,
+    encoder_callback: Option<EncoderCallback>,
+    pot_callback: Option<PotCallback>,
+    gesture_callback: Option<GestureCallback>,
+    connection_callback: Option<ConnectionCallback>,
 }
 
-#[allow(dead_code)]
+/// Access to the LED frame from inside a controller callback.
 pub struct LedHandle<'a> {
     leds: &'a mut [u8; 32],
     dirty: &'a mut bool,
@@ -145,7 +263,12 @@ impl<'a> LedHandle<'a> {
         Self { leds, dirty }
     }
 
-    #[allow(dead_code)]
+    /// Set the brightness of a named LED.
+    pub fn set_led(&mut self, led: LedId, brightness: u8) {
+        self.set_raw(led.index(), brightness);
+    }
+
+    /// Set a raw byte of the LED frame; out-of-range indices are ignored.
     pub fn set_raw(&mut self, idx: usize, value: u8) {
         if let Some(slot) = self.leds.get_mut(idx) {
             *slot = value;
@@ -153,60 +276,93 @@ impl<'a> LedHandle<'a> {
         }
     }
 
-    #[allow(dead_code)]
+    /// Light a raw LED index bright or dim.
     pub fn set_pressed(&mut self, idx: usize, pressed: bool) {
         let value = if pressed { LED_BRIGHT } else { LED_DIM };
         self.set_raw(idx, value);
     }
 }
 
-impl X1Controller {
+impl X1Controller<UsbTransport> {
     /// Connect to the first Kontrol X1 Mk1 discovered on the USB bus.
     pub fn connect() -> rusb::Result<Self> {
         let context = Context::new()?;
-        let mut handle = None;
-
-        for device in context.devices()?.iter() {
-            let desc = device.device_descriptor()?;
-            if desc.vendor_id() == 0x17cc && desc.product_id() == 0x2305 {
-                handle = Some(device.open()?);
-                break;
-            }
-        }
-
-        let handle = match handle {
-            Some(h) => h,
+        match UsbTransport::open(&context)? {
+            Some(transport) => Ok(Self::with_transport(transport)),
             None => {
                 eprintln!("No X1 controller found.");
-                return Err(Error::NoDevice);
+                Err(Error::NoDevice)
             }
-        };
+        }
+    }
 
-        handle.set_active_configuration(1)?;
-        handle.claim_interface(0)?;
-        handle.set_alternate_setting(0, 0)?;
+    /// Create a controller that waits for a Kontrol X1 Mk1 to appear and
+    /// reconnects after it is unplugged or reset.
+    ///
+    /// Polling never fails because of device errors in this mode; instead the
+    /// connection callback is told about every connect and disconnect. The LED
+    /// frame is kept while the device is away and replayed on reconnect.
+    pub fn connect_supervised() -> rusb::Result<Self> {
+        let context = Context::new()?;
+        let controller = Self::supervised(move || UsbTransport::open(&context));
+        if !controller.is_connected() {
+            eprintln!("No X1 controller found; waiting for one to be plugged in.");
+        }
+        Ok(controller)
+    }
+}
+
+impl<T: X1Transport> X1Controller<T> {
+    /// Drive the controller through an already opened transport.
+    pub fn with_transport(transport: T) -> Self {
+        let mut controller = Self::new(None);
+        controller.transport = Some(transport);
+        controller
+    }
+
+    /// Create a supervised controller that obtains its transport from
+    /// `connector`, which returns `Ok(None)` while no device is available.
+    pub fn supervised<F>(connector: F) -> Self
+    where
+        F: FnMut() -> rusb::Result<Option<T>> + Send + 'static,
+    {
+        let mut controller = Self::new(Some(Box::new(connector)));
+        controller.try_reconnect(Instant::now());
+        controller
+    }
 
-        let mut leds = [LED_DIM; 32];
-        leds[0] = 0x0C;
-        leds[31] = LED_DIM;
+    fn new(connector: Option<Connector<T>>) -> Self {
+        let mut leds = [LED_DIM; LED_FRAME_LEN];
+        leds[0] = LED_REPORT_ID;
 
-        Ok(Self {
-            _context: context,
-            handle,
-            input_buf: [0; 24],
+        Self {
+            transport: None,
+            connector,
+            next_connect_attempt: Instant::now(),
+            input_buf: [0; REPORT_LEN],
             timeout: Duration::from_millis(50),
             leds,
             leds_dirty: true,
             last_state: X1State::default(),
             initialized: false,
+            encoder_acceleration: None,
+            encoder_last_moved: [None; 4],
+            pot_filter: PotFilter::new(PotFilterConfig::default()),
+            gestures: GestureRecognizer::default(),
             button_callback: None,
             encoder_callback: None,
             pot_callback: None,
-        })
+            gesture_callback: None,
+            connection_callback: None,
+        }
+    }
+
+    /// Whether a device is currently claimed.
+    pub fn is_connected(&self) -> bool {
+        self.transport.is_some()
     }
 
     /// Enter the controller's polling loop. This blocks until an error occurs.
-    #[allow(dead_code)]
     pub fn run(mut self) -> rusb::Result<()> {
         loop {
             self.poll_once()?;
@@ -214,16 +370,30 @@ impl X1Controller {
     }
 
     /// Perform a single USB poll, firing callbacks as needed.
+    ///
+    /// In supervised mode this also drives reconnection and only returns
+    /// errors that are unrelated to the device itself.
     pub fn poll_once(&mut self) -> rusb::Result<()> {
-        match self
-            .handle
-            .read_bulk(USB_READ_ENDPOINT, &mut self.input_buf, self.timeout)
-        {
+        if self.transport.is_none() {
+            if self.connector.is_none() {
+                return Err(Error::NoDevice);
+            }
+            self.try_reconnect(Instant::now());
+        }
+
+        let result = match self.transport.as_mut() {
+            Some(transport) => transport.read_report(&mut self.input_buf, self.timeout),
+            None => return Ok(()),
+        };
+
+        match result {
             Ok(len) if len == self.input_buf.len() => {
                 let state = X1State::from_buf(&self.input_buf);
                 let now = Instant::now();
 
                 if !self.initialized {
+                    self.pot_filter.reset(&pot_readings(&state));
+                    self.gestures.reset();
                     self.last_state = state;
                     self.initialized = true;
                     self.leds_dirty = true;
@@ -235,16 +405,43 @@ impl X1Controller {
                 self.handle_encoder_changes(&state, now);
                 self.handle_pot_changes(&state, now);
 
-                self.flush_leds()?;
                 self.last_state = state;
+                self.flush_leds()?;
             }
             Ok(_) => {}
             Err(Error::Timeout) => {}
+            Err(err) if self.connector.is_some() && is_disconnect_error(err) => {
+                self.disconnect(err)
+            }
+            Err(err) if self.connector.is_some() => eprintln!("USB read error: {:?}", err),
             Err(err) => return Err(err),
         }
+
+        if self.initialized && self.transport.is_some() {
+            let now = Instant::now();
+            let gestures = self.gestures.poll(now);
+            if !gestures.is_empty() {
+                let state = self.last_state.clone();
+                self.emit_gestures(&state, gestures, now);
+                self.flush_leds()?;
+            }
+        }
         Ok(())
     }
 
+    /// Install a callback to be notified when a supervised controller
+    /// connects to or loses the device. If the device is connected already,
+    /// the callback is told right away.
+    pub fn set_connection_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(ConnectionEvent, Timestamp) + Send + 'static,
+    {
+        self.connection_callback = Some(Box::new(callback));
+        if self.connector.is_some() && self.transport.is_some() {
+            self.notify_connection(ConnectionEvent::Connected, Instant::now());
+        }
+    }
+
     /// Install a callback to be notified about button state transitions.
     pub fn set_button_callback<F>(&mut self, callback: F)
     where
@@ -254,7 +451,6 @@ impl X1Controller {
     }
 
     /// Install a callback to be notified about encoder value changes.
-    #[allow(dead_code)]
     pub fn set_encoder_callback<F>(&mut self, callback: F)
     where
         F: FnMut(&X1State, EncoderEvent, Timestamp, &mut LedHandle) + Send + 'static,
@@ -262,7 +458,38 @@ impl X1Controller {
         self.encoder_callback = Some(Box::new(callback));
     }
 
-    #[allow(dead_code)]
+    /// Install a callback for recognised gestures (clicks, double taps, long
+    /// presses and chords), see [`GestureConfig`].
+    pub fn set_gesture_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(&X1State, GestureEvent, Timestamp, &mut LedHandle) + Send + 'static,
+    {
+        self.gesture_callback = Some(Box::new(callback));
+    }
+
+    /// Replace the gesture timing and chord settings.
+    pub fn set_gesture_config(&mut self, config: GestureConfig) {
+        self.gestures.set_config(config);
+    }
+
+    /// Enable or disable velocity-based acceleration of encoder deltas.
+    pub fn set_encoder_acceleration(&mut self, acceleration: Option<EncoderAcceleration>) {
+        self.encoder_acceleration = acceleration;
+    }
+
+    /// Replace the deadband, smoothing and calibration settings for pots.
+    pub fn set_pot_filter(&mut self, config: PotFilterConfig) {
+        self.pot_filter.set_config(config);
+    }
+
+    /// Set the raw end points of a single pot.
+    pub fn set_pot_calibration(&mut self, id: PotId, calibration: PotCalibration) {
+        let mut config = *self.pot_filter.config();
+        config.calibration[id.index()] = calibration;
+        self.pot_filter.set_config(config);
+    }
+
+    /// Install a callback to be notified about filtered pot changes.
     pub fn set_pot_callback<F>(&mut self, callback: F)
     where
         F: FnMut(&X1State, PotEvent, Timestamp, &mut LedHandle) + Send + 'static,
@@ -271,13 +498,29 @@ impl X1Controller {
     }
 
     /// Remove all registered callbacks.
-    #[allow(dead_code)]
     pub fn clear_callbacks(&mut self) {
         self.button_callback = None;
         self.encoder_callback = None;
         self.pot_callback = None;
+        self.gesture_callback = None;
+        self.connection_callback = None;
+    }
+
+    /// Set the brightness of a named LED.
+    pub fn set_led(&mut self, led: LedId, brightness: u8) {
+        self.set_led_raw(led.index(), brightness);
+    }
+
+    /// Replace the whole LED frame, e.g. with the output of an
+    /// [`LedCompositor`](super::LedCompositor). Byte 0 stays [`LED_REPORT_ID`].
+    pub fn set_led_frame(&mut self, frame: &[u8; LED_FRAME_LEN]) {
+        if self.leds[1..] != frame[1..] {
+            self.leds[1..].copy_from_slice(&frame[1..]);
+            self.leds_dirty = true;
+        }
     }
 
+    /// Set a raw byte of the LED frame; out-of-range indices are ignored.
     pub fn set_led_raw(&mut self, idx: usize, value: u8) {
         if let Some(slot) = self.leds.get_mut(idx) {
             *slot = value;
@@ -285,17 +528,22 @@ impl X1Controller {
         }
     }
 
-    #[allow(dead_code)]
+    /// Light a raw LED index bright or dim.
     pub fn set_led_pressed(&mut self, idx: usize, pressed: bool) {
         let value = if pressed { LED_BRIGHT } else { LED_DIM };
         self.set_led_raw(idx, value);
     }
 
-    #[allow(dead_code)]
+    /// The most recently parsed controller state.
     pub fn last_state(&self) -> &X1State {
         &self.last_state
     }
 
+    /// The transport currently in use, if a device is connected.
+    pub fn transport(&self) -> Option<&T> {
+        self.transport.as_ref()
+    }
+
     fn handle_button_changes(&mut self, state: &X1State, now: Instant) {
         self.emit_button(
             state,
@@ -569,94 +817,56 @@ impl X1Controller {
     }
 
     fn handle_pot_changes(&mut self, state: &X1State, now: Instant) {
-        self.emit_pot(
-            state,
-            now,
-            PotId::Deck1DryWet,
-            state.pot_deck1_dry_wet,
-            self.last_state.pot_deck1_dry_wet,
-        );
-        self.emit_pot(
-            state,
-            now,
-            PotId::Deck1_1,
-            state.pot_deck1_1,
-            self.last_state.pot_deck1_1,
-        );
-        self.emit_pot(
-            state,
-            now,
-            PotId::Deck1_2,
-            state.pot_deck1_2,
-            self.last_state.pot_deck1_2,
-        );
-        self.emit_pot(
-            state,
-            now,
-            PotId::Deck1_3,
-            state.pot_deck1_3,
-            self.last_state.pot_deck1_3,
-        );
-        self.emit_pot(
-            state,
-            now,
-            PotId::Deck2DryWet,
-            state.pot_deck2_dry_wet,
-            self.last_state.pot_deck2_dry_wet,
-        );
-        self.emit_pot(
-            state,
-            now,
-            PotId::Deck2_1,
-            state.pot_deck2_1,
-            self.last_state.pot_deck2_1,
-        );
-        self.emit_pot(
-            state,
-            now,
-            PotId::Deck2_2,
-            state.pot_deck2_2,
-            self.last_state.pot_deck2_2,
-        );
-        self.emit_pot(
-            state,
-            now,
-            PotId::Deck2_3,
-            state.pot_deck2_3,
-            self.last_state.pot_deck2_3,
-        );
+        let readings = pot_readings(state);
+        for id in PotId::ALL {
+            self.emit_pot(state, now, id, readings[id.index()]);
+        }
     }
 
     fn emit_button(&mut self, state: &X1State, now: Instant, id: ButtonId, new: bool, old: bool) {
         if new == old {
             return;
         }
+        let kind = if new {
+            ButtonEventKind::Pressed
+        } else {
+            ButtonEventKind::Released
+        };
+        let event = ButtonEvent {
+            id,
+            kind,
+            modifiers: Modifiers::from_state(state),
+        };
         if let Some(mut cb) = self.button_callback.take() {
-            let kind = if new {
-                ButtonEventKind::Pressed
-            } else {
-                ButtonEventKind::Released
-            };
-            let modifiers = Modifiers::from_state(state);
             let mut handle = LedHandle::new(&mut self.leds, &mut self.leds_dirty);
-            cb(
-                state,
-                ButtonEvent {
-                    id,
-                    kind,
-                    modifiers,
-                },
-                now,
-                &mut handle,
-            );
+            cb(state, event, now, &mut handle);
             self.button_callback = Some(cb);
         }
+
+        let gestures = self.gestures.on_button(event, now);
+        self.emit_gestures(state, gestures, now);
+    }
+
+    fn emit_gestures(&mut self, state: &X1State, gestures: Vec<GestureEvent>, now: Instant) {
+        if let Some(mut cb) = self.gesture_callback.take() {
+            for gesture in gestures {
+                let mut handle = LedHandle::new(&mut self.leds, &mut self.leds_dirty);
+                cb(state, gesture, now, &mut handle);
+            }
+            self.gesture_callback = Some(cb);
+        }
     }
 
     fn emit_encoder(&mut self, state: &X1State, now: Instant, id: EncoderId, new: u8, old: u8) {
         if new == old {
             return;
         }
+        let delta = wrap_delta(new, old);
+        let last_moved = self.encoder_last_moved[id.index()].replace(now);
+        let accelerated_delta = match &self.encoder_acceleration {
+            Some(accel) => accel.apply(delta, last_moved.map(|t| now.duration_since(t))),
+            None => i32::from(delta),
+        };
         if let Some(mut cb) = self.encoder_callback.take() {
             let modifiers = Modifiers::from_state(state);
             let mut handle = LedHandle::new(&mut self.leds, &mut self.leds_dirty);
@@ -666,6 +876,8 @@ impl X1Controller {
                     id,
                     value: new,
                     previous: old,
+                    delta,
+                    accelerated_delta,
                     modifiers,
                 },
                 now,
@@ -675,10 +887,10 @@ impl X1Controller {
         }
     }
 
-    fn emit_pot(&mut self, state: &X1State, now: Instant, id: PotId, new: u16, old: u16) {
-        if new == old {
+    fn emit_pot(&mut self, state: &X1State, now: Instant, id: PotId, raw: u16) {
+        let Some(change) = self.pot_filter.update(id.index(), raw) else {
             return;
-        }
+        };
         if let Some(mut cb) = self.pot_callback.take() {
             let modifiers = Modifiers::from_state(state);
             let mut handle = LedHandle::new(&mut self.leds, &mut self.leds_dirty);
@@ -686,8 +898,10 @@ impl X1Controller {
                 state,
                 PotEvent {
                     id,
-                    value: new,
-                    previous: old,
+                    value: change.value,
+                    previous: change.previous,
+                    raw,
+                    normalized: change.normalized,
                     modifiers,
                 },
                 now,
@@ -701,30 +915,326 @@ impl X1Controller {
         if !self.leds_dirty {
             return Ok(());
         }
+        let Some(transport) = self.transport.as_mut() else {
+            return Ok(());
+        };
 
-        if let Err(err) = self
-            .handle
-            .write_bulk(USB_WRITE_ENDPOINT, &self.leds, self.timeout)
-        {
+        if let Err(err) = transport.write_leds(&self.leds, self.timeout) {
             eprintln!("USB write error: {:?}", err);
+            if self.connector.is_some() && is_disconnect_error(err) {
+                self.disconnect(err);
+            }
         } else {
-            let mut ack = [0u8; 1];
-            match self
-                .handle
-                .read_bulk(USB_UNLOCK_ENDPOINT, &mut ack, self.timeout)
-            {
-                Ok(_) | Err(Error::Timeout) => {}
-                Err(err) => eprintln!("USB unlock read error: {:?}", err),
+            if let Err(err) = transport.read_unlock_ack(self.timeout) {
+                eprintln!("USB unlock read error: {:?}", err);
             }
             self.leds_dirty = false;
         }
 
         Ok(())
     }
+
+    fn try_reconnect(&mut self, now: Instant) {
+        if now < self.next_connect_attempt {
+            return;
+        }
+        self.next_connect_attempt = now + RECONNECT_INTERVAL;
+
+        let Some(connector) = self.connector.as_mut() else {
+            return;
+        };
+        match connector() {
+            Ok(Some(transport)) => {
+                self.transport = Some(transport);
+                // Treat the first report as a fresh baseline and replay the LED frame.
+                self.initialized = false;
+                self.leds_dirty = true;
+                self.notify_connection(ConnectionEvent::Connected, now);
+            }
+            Ok(None) => {}
+            Err(err) => eprintln!("Failed to open X1 controller: {}", err),
+        }
+    }
+
+    fn disconnect(&mut self, err: Error) {
+        if self.transport.take().is_none() {
+            return;
+        }
+        eprintln!("X1 controller lost: {}", err);
+        let now = Instant::now();
+        self.next_connect_attempt = now + RECONNECT_INTERVAL;
+        self.release_held_buttons(now);
+        self.notify_connection(ConnectionEvent::Disconnected, now);
+    }
+
+    /// Report every held button as released; the device is gone and cannot
+    /// do so itself. The releases complete no gestures.
+    fn release_held_buttons(&mut self, now: Instant) {
+        if !self.initialized {
+            return;
+        }
+        self.gestures.reset();
+        let released = X1State::default();
+        self.handle_button_changes(&released, now);
+        self.last_state = released;
+        self.initialized = false;
+    }
+
+    fn notify_connection(&mut self, event: ConnectionEvent, now: Instant) {
+        if let Some(cb) = self.connection_callback.as_mut() {
+            cb(event, now);
+        }
+    }
+}
+
+/// Raw pot readings in [`PotId::ALL`] order.
+fn pot_readings(state: &X1State) -> [u16; 8] {
+    [
+        state.pot_deck1_dry_wet,
+        state.pot_deck1_1,
+        state.pot_deck1_2,
+        state.pot_deck1_3,
+        state.pot_deck2_dry_wet,
+        state.pot_deck2_1,
+        state.pot_deck2_2,
+        state.pot_deck2_3,
+    ]
 }
 
-const USB_READ_ENDPOINT: u8 = 0x84;
-const USB_WRITE_ENDPOINT: u8 = 0x01;
-const USB_UNLOCK_ENDPOINT: u8 = 0x81;
+/// Errors after which the transport is unusable and must be reopened.
+fn is_disconnect_error(err: Error) -> bool {
+    matches!(
+        err,
+        Error::NoDevice | Error::Io | Error::NotFound | Error::Pipe
+    )
+}
+
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
 pub const LED_DIM: u8 = 0x05;
 pub const LED_BRIGHT: u8 = 0x7F;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::x1_controller::MockTransport;
+
+    /// Build an input report with the given `(group, bit)` buttons held.
+    fn report(buttons: &[(usize, u8)], encoders: [u8; 2], pots: [u16; 8]) -> [u8; REPORT_LEN] {
+        let mut buf = [0u8; REPORT_LEN];
+        for &(group, bit) in buttons {
+            buf[1 + group] |= 1 << bit;
+        }
+        buf[6] = encoders[0];
+        buf[7] = encoders[1];
+        for (i, pot) in pots.iter().enumerate() {
+            buf[8 + i * 2..10 + i * 2].copy_from_slice(&pot.to_be_bytes());
+        }
+        buf
+    }
+
+    const DECK1_PLAY: (usize, u8) = (0, 0);
+    const SHIFT: (usize, u8) = (4, 4);
+
+    #[test]
+    fn first_report_is_a_baseline() {
+        let mock = MockTransport::new();
+        let mut controller = X1Controller::with_transport(mock.clone());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        controller.set_button_callback(move |_, event, _, _| sink.lock().unwrap().push(event));
+
+        mock.push_report(report(&[DECK1_PLAY], [0; 2], [0; 8]));
+        controller.poll_once().unwrap();
+
+        assert!(events.lock().unwrap().is_empty());
+        assert_eq!(mock.led_frames().len(), 1);
+    }
+
+    #[test]
+    fn dispatches_button_transitions_with_modifiers() {
+        let mock = MockTransport::new();
+        let mut controller = X1Controller::with_transport(mock.clone());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        controller.set_button_callback(move |_, event, _, _| sink.lock().unwrap().push(event));
+
+        mock.push_report(report(&[], [0; 2], [0; 8]));
+        mock.push_report(report(&[SHIFT], [0; 2], [0; 8]));
+        mock.push_report(report(&[SHIFT, DECK1_PLAY], [0; 2], [0; 8]));
+        mock.push_report(report(&[], [0; 2], [0; 8]));
+        for _ in 0..4 {
+            controller.poll_once().unwrap();
+        }
+
+        let events = events.lock().unwrap();
+        let summary: Vec<_> = events
+            .iter()
+            .map(|e| (e.id, e.kind, e.modifiers.shift))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (ButtonId::Shift, ButtonEventKind::Pressed, true),
+                (ButtonId::Deck1Play, ButtonEventKind::Pressed, true),
+                (ButtonId::Shift, ButtonEventKind::Released, false),
+                (ButtonId::Deck1Play, ButtonEventKind::Released, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn dispatches_encoder_and_pot_changes() {
+        let mock = MockTransport::new();
+        let mut controller = X1Controller::with_transport(mock.clone());
+        let encoders = Arc::new(Mutex::new(Vec::new()));
+        let pots = Arc::new(Mutex::new(Vec::new()));
+        let enc_sink = encoders.clone();
+        let pot_sink = pots.clone();
+        controller.set_encoder_callback(move |_, event, _, _| {
+            enc_sink
+                .lock()
+                .unwrap()
+                .push((event.id, event.value, event.previous, event.delta))
+        });
+        controller.set_pot_callback(move |_, event, _, _| {
+            pot_sink.lock().unwrap().push((event.id, event.value))
+        });
+
+        mock.push_report(report(&[], [0x00, 0x00], [0; 8]));
+        let mut moved = [0u16; 8];
+        moved[4] = 0x0123;
+        mock.push_report(report(&[], [0x03, 0x00], moved));
+        mock.push_report(report(&[], [0x03, 0x0E], moved));
+        for _ in 0..3 {
+            controller.poll_once().unwrap();
+        }
+
+        assert_eq!(
+            *encoders.lock().unwrap(),
+            vec![
+                (EncoderId::Deck1Browse, 3, 0, 3),
+                (EncoderId::Deck1Loop, 14, 0, -2),
+            ]
+        );
+        assert_eq!(*pots.lock().unwrap(), vec![(PotId::Deck1DryWet, 0x0123)]);
+    }
+
+    #[test]
+    fn flushes_leds_only_when_dirty() {
+        let mock = MockTransport::new();
+        let mut controller = X1Controller::with_transport(mock.clone());
+        controller.set_button_callback(|_, event, _, leds| {
+            if event.kind == ButtonEventKind::Pressed {
+                leds.set_led(LedId::Deck1Sync, LED_BRIGHT);
+            }
+        });
+
+        mock.push_report(report(&[], [0; 2], [0; 8]));
+        mock.push_report(report(&[], [0; 2], [0; 8]));
+        mock.push_report(report(&[DECK1_PLAY], [0; 2], [0; 8]));
+        for _ in 0..3 {
+            controller.poll_once().unwrap();
+        }
+
+        let frames = mock.led_frames();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0][0], LED_REPORT_ID);
+        assert_eq!(frames[1][LedId::Deck1Sync.index()], LED_BRIGHT);
+    }
+
+    #[test]
+    fn supervised_controller_reconnects_and_replays_leds() {
+        let mock = MockTransport::new();
+        let connector_mock = mock.clone();
+        let mut controller = X1Controller::supervised(move || Ok(Some(connector_mock.clone())));
+        let connections = Arc::new(Mutex::new(Vec::new()));
+        let sink = connections.clone();
+        controller.set_connection_callback(move |event, _| sink.lock().unwrap().push(event));
+        assert!(controller.is_connected());
+
+        controller.set_led(LedId::Deck1Sync, LED_BRIGHT);
+        mock.push_error(Error::NoDevice);
+        controller.poll_once().unwrap();
+        assert!(!controller.is_connected());
+
+        controller.next_connect_attempt = Instant::now();
+        mock.push_report(report(&[], [0; 2], [0; 8]));
+        controller.poll_once().unwrap();
+
+        assert!(controller.is_connected());
+        assert_eq!(
+            *connections.lock().unwrap(),
+            vec![
+                ConnectionEvent::Connected,
+                ConnectionEvent::Disconnected,
+                ConnectionEvent::Connected
+            ]
+        );
+        assert_eq!(
+            mock.last_led_frame().unwrap()[LedId::Deck1Sync.index()],
+            LED_BRIGHT
+        );
+    }
+
+    #[test]
+    fn held_buttons_are_released_on_disconnect() {
+        let mock = MockTransport::new();
+        let connector_mock = mock.clone();
+        let mut controller = X1Controller::supervised(move || Ok(Some(connector_mock.clone())));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        controller.set_button_callback(move |_, event, _, _| {
+            sink.lock().unwrap().push((event.id, event.kind))
+        });
+        let gestures = Arc::new(Mutex::new(Vec::new()));
+        let gesture_sink = gestures.clone();
+        controller.set_gesture_callback(move |_, event, _, _| {
+            gesture_sink.lock().unwrap().push(event.kind)
+        });
+
+        mock.push_report(report(&[], [0; 2], [0; 8]));
+        mock.push_report(report(&[DECK1_PLAY], [0; 2], [0; 8]));
+        mock.push_error(Error::NoDevice);
+        for _ in 0..3 {
+            controller.poll_once().unwrap();
+        }
+
+        assert!(!controller.is_connected());
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                (ButtonId::Deck1Play, ButtonEventKind::Pressed),
+                (ButtonId::Deck1Play, ButtonEventKind::Released),
+            ]
+        );
+        assert!(gestures.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn transient_read_errors_keep_the_device_connected() {
+        let mock = MockTransport::new();
+        let connector_mock = mock.clone();
+        let mut controller = X1Controller::supervised(move || Ok(Some(connector_mock.clone())));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        controller.set_button_callback(move |_, event, _, _| {
+            sink.lock().unwrap().push((event.id, event.kind))
+        });
+
+        mock.push_report(report(&[], [0; 2], [0; 8]));
+        mock.push_error(Error::Overflow);
+        mock.push_error(Error::Interrupted);
+        mock.push_report(report(&[DECK1_PLAY], [0; 2], [0; 8]));
+        for _ in 0..4 {
+            controller.poll_once().unwrap();
+        }
+
+        assert!(controller.is_connected());
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![(ButtonId::Deck1Play, ButtonEventKind::Pressed)]
+        );
+    }
+}
@@ -1,3 +1,4 @@
+/// Snapshot of every control parsed from one 24-byte input report.
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct X1State {
     // Buttons
@@ -57,10 +58,10 @@ impl X1State {
     /// Parse controller state directly from the raw USB input buffer.
     pub fn from_buf(buf: &[u8; 24]) -> Self {
         let mut pots_raw = [0u16; 8];
-        for i in 0..8 {
+        for (i, pot) in pots_raw.iter_mut().enumerate() {
             let idx = 8 + i * 2;
             if idx + 1 < buf.len() {
-                pots_raw[i] = u16::from_be_bytes([buf[idx], buf[idx + 1]]);
+                *pot = u16::from_be_bytes([buf[idx], buf[idx + 1]]);
             }
         }
 